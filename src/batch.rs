@@ -0,0 +1,264 @@
+//! Groups of mutations that are applied, checked and committed together.
+
+use crate::{editor::apply_edits, mutation::Mutation, source::SourceFile};
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Why a mutation could not be added to a batch.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Conflict {
+    /// The very same mutation is already in the batch.
+    Duplicate,
+    /// Another mutation in the batch edits an overlapping region of the same file.
+    Overlap,
+}
+
+/// A set of mutations, possibly spanning several files, that are applied to the
+/// tree together.
+///
+/// No two mutations in a batch edit overlapping regions of the same file, so every one of
+/// them can be applied.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    mutations: Vec<Mutation>,
+
+    /// Number of mutations refused because they were already in the batch.
+    duplicates: usize,
+
+    /// Number of mutations refused because they overlapped one in the batch.
+    overlaps: usize,
+}
+
+impl Batch {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            mutations: Vec::with_capacity(capacity),
+            ..Default::default()
+        }
+    }
+
+    /// Return a batch of just one mutation.
+    pub fn single(mutation: Mutation) -> Self {
+        Self {
+            mutations: vec![mutation],
+            ..Default::default()
+        }
+    }
+
+    /// Add `mutation` to the batch unless it conflicts with a mutation already in it.
+    pub fn push(&mut self, mutation: Mutation) -> Result<(), Conflict> {
+        match self.conflict(&mutation) {
+            Some(Conflict::Duplicate) => {
+                self.duplicates += 1;
+                Err(Conflict::Duplicate)
+            }
+            Some(Conflict::Overlap) => {
+                self.overlaps += 1;
+                Err(Conflict::Overlap)
+            }
+            None => {
+                self.mutations.push(mutation);
+                Ok(())
+            }
+        }
+    }
+
+    /// Return how `mutation` conflicts with the batch, if at all.
+    pub fn conflict(&self, mutation: &Mutation) -> Option<Conflict> {
+        self.mutations
+            .iter()
+            .filter(|m| m.source_file.path() == mutation.source_file.path())
+            .filter(|m| m.edit().overlaps(&mutation.edit()))
+            .map(|m| {
+                if m == mutation {
+                    Conflict::Duplicate
+                } else {
+                    Conflict::Overlap
+                }
+            })
+            .min_by_key(|c| *c != Conflict::Duplicate)
+    }
+
+    /// Number of mutations refused because they were already in the batch.
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+
+    /// Number of mutations refused because they overlapped one in the batch.
+    pub fn overlaps(&self) -> usize {
+        self.overlaps
+    }
+
+    pub fn len(&self) -> usize {
+        self.mutations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mutations.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mutation> {
+        self.mutations.iter()
+    }
+
+    /// Return the text of every file touched by the batch with all of that file's
+    /// mutations applied together.
+    pub fn mutate(&self) -> Result<Vec<(&SourceFile, String)>> {
+        let mut by_file: BTreeMap<&Path, Vec<&Mutation>> = BTreeMap::new();
+        for mutation in &self.mutations {
+            by_file
+                .entry(mutation.source_file.path())
+                .or_default()
+                .push(mutation);
+        }
+        by_file
+            .into_values()
+            .map(|mutations| {
+                let source_file = &mutations[0].source_file;
+                if mutations
+                    .iter()
+                    .any(|m| m.source_file.code != source_file.code)
+                {
+                    return Err(anyhow!(
+                        "mutations of {:?} were generated from different versions of the file",
+                        source_file.path()
+                    ));
+                }
+                let edits: Vec<_> = mutations.iter().map(|m| m.edit()).collect();
+                Ok((source_file, apply_edits(&source_file.code, &edits)?))
+            })
+            .collect()
+    }
+
+    /// Rewrite every file touched by the batch.
+    pub fn apply(&self) -> Result<()> {
+        for (source_file, code) in self.mutate()? {
+            source_file.rewrite(code)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite every touched file as found under `root` instead of the tree the mutations
+    /// came from, such as in another checkout of the same commit.
+    pub fn apply_in(&self, root: &Path) -> Result<()> {
+        for (source_file, code) in self.mutate()? {
+            let path = root.join(source_file.tree_relative_path());
+            std::fs::write(&path, code).with_context(|| format!("failed to write {:?}", path))?;
+        }
+        Ok(())
+    }
+
+    /// True if every file touched by the batch still holds the code its mutations were
+    /// generated from, so that the batch can still be applied.
+    pub fn is_current(&self) -> Result<bool> {
+        for (source_file, _) in self.mutate()? {
+            let code = std::fs::read_to_string(source_file.path())
+                .with_context(|| format!("failed to read {:?}", source_file.path()))?;
+            if code.replace("\r\n", "\n") != *source_file.code {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Undo [Batch::apply], restoring every touched file to the code the mutations were
+    /// generated from.
+    pub fn revert(&self) -> Result<()> {
+        for (source_file, _) in self.mutate()? {
+            source_file.rewrite(source_file.code.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl Extend<Mutation> for Batch {
+    /// Add each mutation in turn, refusing and counting the conflicting ones.
+    fn extend<I: IntoIterator<Item = Mutation>>(&mut self, iter: I) {
+        for mutation in iter {
+            let _ = self.push(mutation);
+        }
+    }
+}
+
+impl FromIterator<Mutation> for Batch {
+    fn from_iter<I: IntoIterator<Item = Mutation>>(iter: I) -> Self {
+        let mut batch = Batch::default();
+        batch.extend(iter);
+        batch
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn refuses_duplicate_and_overlapping_mutations() {
+        let sf = SourceFile::from_code(Path::new("src/lib.rs"), "pub struct A;\npub struct B;\n");
+        let mutations = sf.mutations().unwrap();
+        let for_a: Vec<_> = mutations
+            .iter()
+            .filter(|m| m.span().start.line == 1)
+            .collect();
+        let for_b: Vec<_> = mutations
+            .iter()
+            .filter(|m| m.span().start.line == 2)
+            .collect();
+
+        let mut batch = Batch::default();
+        assert_eq!(batch.push(for_a[0].clone()), Ok(()));
+        assert_eq!(batch.push(for_a[0].clone()), Err(Conflict::Duplicate));
+        assert_eq!(batch.push(for_a[1].clone()), Err(Conflict::Overlap));
+        assert_eq!(batch.push(for_b[0].clone()), Ok(()));
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.duplicates(), 1);
+        assert_eq!(batch.overlaps(), 1);
+
+        assert_eq!(
+            batch.mutate().unwrap()[0].1,
+            "pub(crate) struct A;\npub(crate) struct B;\n"
+        );
+    }
+
+    #[test]
+    fn apply_in_another_checkout_while_current() {
+        let tree = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        for root in [tree.path(), other.path()] {
+            std::fs::create_dir(root.join("src")).unwrap();
+            std::fs::write(root.join("src/lib.rs"), "pub struct A;\n").unwrap();
+        }
+        let read = |root: &Path| std::fs::read_to_string(root.join("src/lib.rs")).unwrap();
+        let sf = SourceFile::new(
+            tree.path(),
+            Path::new("src/lib.rs"),
+            "demo",
+            "demo",
+            "crate",
+        )
+        .unwrap();
+        let batch: Batch = sf
+            .mutations()
+            .unwrap()
+            .into_iter()
+            .filter(|m| m.op == crate::mutation::MutationOp::ToVisInherited)
+            .collect();
+        assert!(batch.is_current().unwrap());
+
+        batch.apply_in(other.path()).unwrap();
+        assert_eq!(read(other.path()), "struct A;\n");
+        assert_eq!(read(tree.path()), "pub struct A;\n");
+        assert!(batch.is_current().unwrap());
+
+        std::fs::write(
+            tree.path().join("src/lib.rs"),
+            "pub struct A;\npub struct B;\n",
+        )
+        .unwrap();
+        assert!(!batch.is_current().unwrap());
+    }
+}
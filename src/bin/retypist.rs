@@ -1,11 +1,20 @@
+use anyhow::{anyhow, Result};
 use argh::FromArgs;
 use retypist::{
-    cargo::{run_cargo, CargoResult},
-    git::run_git,
+    config::{Config, Stage},
+    editor::LineColumn,
+    git::{uncommitted_changes, Stash},
     interrupt,
+    mutation::MutationOp,
+    session::{Session, Strategy, DEFAULT_COMMIT_EVERY, DEFAULT_MAX_IDLE_BATCHES},
     source::SourceTree,
+    state::State,
 };
-use std::path::PathBuf;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 
 /// Mutate a project, ideally in beneficial ways
 #[derive(FromArgs, PartialEq, Debug)]
@@ -13,38 +22,326 @@ struct Args {
     /// rust crate directory to examine.
     #[argh(option, short = 'd', default = r#"PathBuf::from(".")"#)]
     dir: PathBuf,
+
+    /// print a diff for each mutation of one candidate batch and exit without
+    /// changing any files.
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// seed for the random choice of mutations, to reproduce an earlier run.
+    #[argh(option)]
+    seed: Option<u64>,
+
+    /// how to choose mutations: `random` batches, each candidate once in order
+    /// with `exhaustive`, or one at a time on top of each other with `greedy`.
+    #[argh(option, default = "Strategy::Random")]
+    strategy: Strategy,
+
+    /// when a batch fails, bisect it to commit the largest subset that passes.
+    #[argh(switch)]
+    bisect: bool,
+
+    /// stop the random strategy after this many batches in a row that accept
+    /// or reject nothing.
+    #[argh(option, default = "DEFAULT_MAX_IDLE_BATCHES")]
+    max_idle_batches: usize,
+
+    /// with the greedy strategy, commit after this many accepted mutations.
+    #[argh(option, default = "DEFAULT_COMMIT_EVERY")]
+    commit_every: usize,
+
+    /// number of batches to check at the same time, each in a git worktree
+    /// under target/retypist/worktrees. Only the random strategy supports
+    /// more than one.
+    #[argh(option, default = "1")]
+    jobs: usize,
+
+    /// stash uncommitted changes while running and restore them on exit,
+    /// instead of refusing to run on a tree that has any.
+    #[argh(switch)]
+    stash: bool,
+
+    /// branch to commit to, created if it doesn't exist and suffixed with `-2` and
+    /// so on if it lacks checked out commits; `{{date}}` stands for today's date. Defaults to the one in retypist.toml, or
+    /// `retypist/{{date}}`. Empty to commit to the checked out branch.
+    #[argh(option)]
+    branch: Option<String>,
+
+    /// author of the commits, like `Name <email>`, instead of git's user.
+    #[argh(option)]
+    author: Option<String>,
+
+    /// trailer to add to every commit message, like `Signed-off-by: Name
+    /// <email>`, on top of those in retypist.toml; repeat for more.
+    #[argh(option)]
+    trailer: Vec<String>,
+
+    /// forget the outcomes of mutations remembered from earlier runs.
+    #[argh(switch)]
+    forget: bool,
+
+    /// program that checks the tree, instead of the one in retypist.toml or
+    /// `cargo`.
+    #[argh(option)]
+    check_command: Option<String>,
+
+    /// stage of the check, one of `check`, `clippy`, `test` or `doc`, or
+    /// NAME=ARGS to run the check program with whitespace-separated ARGS;
+    /// repeat to run several in order. Replaces the stages in retypist.toml or
+    /// the default `check`.
+    #[argh(option)]
+    stage: Vec<Stage>,
+
+    /// argument to the check program, making up a single stage; repeat for
+    /// more. Replaces the stages in retypist.toml or the default `check`.
+    #[argh(option)]
+    check_arg: Vec<String>,
+
+    /// feature to enable in the check, on top of those in retypist.toml;
+    /// repeat for more.
+    #[argh(option)]
+    features: Vec<String>,
+
+    /// check with all features enabled.
+    #[argh(switch)]
+    all_features: bool,
+
+    /// check without the default features.
+    #[argh(switch)]
+    no_default_features: bool,
+
+    /// check the whole workspace after every mutation, not only the mutated
+    /// packages and those that depend on them.
+    #[argh(switch)]
+    whole_workspace: bool,
+
+    /// environment variable for the check, as KEY=VALUE; repeat for more.
+    #[argh(option, from_str_fn(parse_env))]
+    env: Vec<(String, String)>,
+
+    /// seconds any cargo command may run before it is killed, 0 for no limit.
+    /// Defaults to the value in retypist.toml, or no limit.
+    #[argh(option)]
+    cargo_timeout: Option<u64>,
+
+    /// seconds any git command may run before it is killed, 0 for no limit.
+    /// Defaults to the value in retypist.toml, or 60.
+    #[argh(option)]
+    git_timeout: Option<u64>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum Command {
+    List(List),
+}
+
+/// List every candidate mutation in the tree without changing anything.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "list")]
+struct List {
+    /// output format, `text` or `json`.
+    #[argh(option, default = "Format::Text")]
+    format: Format,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(anyhow!("unknown format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// One candidate mutation, as shown by `retypist list`.
+#[derive(Serialize)]
+struct ListEntry<'a> {
+    path: &'a Path,
+    package: &'a str,
+    target: &'a str,
+    module: &'a str,
+    start: LineColumn,
+    end: LineColumn,
+    op: MutationOp,
+    item: &'a str,
+}
+
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+/// Combine the configuration in retypist.toml with the overrides in `args`.
+fn config(root: &Path, args: &Args) -> Result<Config> {
+    let mut config = Config::load(root)?;
+    if let Some(cargo) = args.cargo_timeout {
+        config.timeouts.cargo = cargo;
+    }
+    if let Some(git) = args.git_timeout {
+        config.timeouts.git = git;
+    }
+    if let Some(branch) = &args.branch {
+        config.commit.branch = branch.clone();
+    }
+    if let Some(author) = &args.author {
+        config.commit.author = Some(author.clone());
+    }
+    config.commit.trailers.extend(args.trailer.iter().cloned());
+    let check = &mut config.check;
+    if let Some(command) = &args.check_command {
+        check.command = command.clone();
+    }
+    if !args.stage.is_empty() && !args.check_arg.is_empty() {
+        return Err(anyhow!("--stage and --check-arg can't be combined"));
+    }
+    if !args.stage.is_empty() {
+        check.stages = args.stage.clone();
+    }
+    if !args.check_arg.is_empty() {
+        check.stages = vec![Stage::custom(args.check_arg.clone())];
+    }
+    check.features.extend(args.features.iter().cloned());
+    check.all_features |= args.all_features;
+    check.no_default_features |= args.no_default_features;
+    check.affected_only &= !args.whole_workspace;
+    check.env.extend(args.env.iter().cloned());
+    Ok(config)
+}
+
+fn list(tree: &SourceTree, format: Format) -> Result<()> {
+    let entries = tree.mutations().map(|m| ListEntry {
+        path: m.source_file.tree_relative_path(),
+        package: m.source_file.package(),
+        target: m.source_file.target(),
+        module: m.source_file.module_path(),
+        start: m.span().start,
+        end: m.span().end,
+        op: m.op,
+        item: m.item(),
+    });
+    match format {
+        Format::Text => {
+            for e in entries {
+                println!(
+                    "{}:{}:{}-{}:{}: {:?} {}",
+                    e.path.display(),
+                    e.start.line,
+                    e.start.column,
+                    e.end.line,
+                    e.end.column,
+                    e.op,
+                    e.item
+                );
+            }
+        }
+        Format::Json => {
+            let entries: Vec<_> = entries.collect();
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+        }
+    }
+    Ok(())
 }
 
 fn main() {
     let args: Args = argh::from_env();
     interrupt::install_handler();
-    let tree = SourceTree::new(&args.dir).unwrap();
-    loop {
-        run_git(&["checkout", "."], tree.root()).unwrap();
-        for mut mutation in tree.mutation().unwrap() {
-            let change = mutation.mutate();
-            let source_file = &mut mutation.source_file;
-            source_file.rewrite(change).unwrap();
+    match run(&args) {
+        Ok(()) => {}
+        Err(err) if interrupt::is_interrupted(&err) => {
+            println!("INTERRUPTED");
+            process::exit(interrupt::EXIT_INTERRUPTED);
         }
-        // TODO must allow users to configure their cargo args
-        match run_cargo(&["check", "--tests", "--workspace", "--features", "default metrics-benches codecs-benches language-benches remap-benches statistic-benches dnstap-benches benches"], tree.root()) {
-            Ok(res) => match res {
-                CargoResult::Success => {
-                    println!("PASS");
-                    run_cargo(&["fmt"], tree.root()).unwrap();
-                    run_git(
-                        &["commit", "-am", "[RETYPIST] applied modifications"],
-                        tree.root(),
-                    )
-                    .unwrap();
-                }
-                CargoResult::Failure => {
-                    println!("FAIL");
-                }
-            },
-            Err(err) => {
-                println!("ERROR {:?}", err);
-            }
+        Err(err) => {
+            println!("ERROR {:?}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<()> {
+    if let Some(Command::List(list_args)) = &args.command {
+        let timeouts = config(&args.dir, args)?.timeouts;
+        let tree = SourceTree::new(&args.dir, timeouts.cargo())?;
+        return list(&tree, list_args.format);
+    }
+    // Read before any stash hides an uncommitted retypist.toml.
+    let config = config(&args.dir, args)?;
+    if args.dry_run {
+        let mut session = session(args, config)?;
+        for mutation in session.sample()?.iter() {
+            println!("# {}", mutation);
+            print!("{}", mutation.diff());
         }
+        return Ok(());
     }
+    let stash = protect_changes(&args.dir, args.stash, config.timeouts.git())?;
+    let result = session(args, config).and_then(|mut session| {
+        let result = session.run(args.strategy);
+        print!("{}", session.summary());
+        result
+    });
+    if let Some(stash) = stash {
+        println!("UNSTASH restoring uncommitted changes");
+        match stash.pop() {
+            Ok(()) => {}
+            Err(err) if result.is_ok() => return Err(err),
+            // The session's error, such as an interrupt, still decides the exit code.
+            Err(err) => eprintln!("ERROR {:?}", err),
+        }
+    }
+    result
+}
+
+/// Refuse to run on a tree with uncommitted changes, which reverting mutations would
+/// destroy, or stash them if `stash` is set, giving git up to `timeout`.
+fn protect_changes(dir: &Path, stash: bool, timeout: Option<Duration>) -> Result<Option<Stash>> {
+    let changes = uncommitted_changes(dir, timeout)?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    if !stash {
+        return Err(anyhow!(
+            "refusing to run on a tree with uncommitted changes, commit them or pass --stash:\n{}",
+            changes.join("\n")
+        ));
+    }
+    println!("STASH {} uncommitted changes", changes.len());
+    Stash::push(dir, timeout).map(Some)
+}
+
+/// Open the tree at `args.dir` and set up a session over it.
+fn session(args: &Args, config: Config) -> Result<Session> {
+    let tree = SourceTree::new(&args.dir, config.timeouts.cargo())?;
+    let seed = args.seed.unwrap_or_else(rand::random);
+    println!("SEED {}", seed);
+    let state = if args.forget {
+        State::empty(tree.root())
+    } else {
+        State::load(tree.root())?
+    };
+    println!("STATE {} remembered outcomes", state.len());
+    Ok(Session::new(tree, seed)
+        .state(state)
+        .bisect(args.bisect)
+        .max_idle_batches(args.max_idle_batches)
+        .commit_every(args.commit_every)
+        .jobs(args.jobs)
+        .check(config.check)
+        .commit(config.commit)
+        .timeouts(config.timeouts))
 }
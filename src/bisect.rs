@@ -0,0 +1,125 @@
+//! Finding the largest subset of a failing set of changes that passes, in the style of
+//! delta debugging.
+
+use crate::pipeline::Verdict;
+use anyhow::Result;
+use std::fmt;
+
+/// How [reduce] checks changes and records what it finds out about them.
+pub trait Bisect<T> {
+    /// Check `trial`, applied to the committed tree, and undo it again.
+    fn try_changes(&mut self, trial: &[T]) -> Result<Verdict>;
+
+    /// Record that `change` fails `stage` on its own, as shown by the latest trial.
+    fn reject_change(&mut self, change: &T, stage: &str) -> Result<()>;
+
+    /// Record that checking `changes` ran out of time.
+    fn time_out_changes(&mut self, changes: &[T]) -> Result<()>;
+}
+
+/// Given that `passing` passes but `passing` plus `failing` fails `stage`, move as many
+/// of `failing` into `passing` as possible by checking halves of it.
+///
+/// A change is rejected only if it fails on its own. One that fails only together with
+/// `passing` is left undecided, to be tried again on top of them once they are
+/// committed.
+pub fn reduce<T, B>(
+    bisect: &mut B,
+    passing: &mut Vec<T>,
+    mut failing: Vec<T>,
+    stage: &str,
+) -> Result<()>
+where
+    T: Clone + fmt::Display,
+    B: Bisect<T>,
+{
+    if failing.len() == 1 {
+        let change = failing.pop().unwrap();
+        if passing.is_empty() {
+            return bisect.reject_change(&change, stage);
+        }
+        println!("ALONE {}", change);
+        return match bisect.try_changes(std::slice::from_ref(&change))? {
+            Verdict::Pass => {
+                println!("UNDECIDED {} fails only with other mutations", change);
+                Ok(())
+            }
+            Verdict::Fail(stage) => bisect.reject_change(&change, &stage),
+            Verdict::TimedOut(_) => bisect.time_out_changes(&[change]),
+        };
+    }
+    let second_half = failing.split_off(failing.len() / 2);
+    for half in [failing, second_half] {
+        println!("BISECT {} + {} mutations", passing.len(), half.len());
+        let trial: Vec<T> = passing.iter().chain(half.iter()).cloned().collect();
+        match bisect.try_changes(&trial)? {
+            Verdict::Pass => passing.extend(half),
+            Verdict::Fail(stage) => reduce(bisect, passing, half, &stage)?,
+            Verdict::TimedOut(_) => bisect.time_out_changes(&half)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// Fails any trial with 3, or with both 5 and 6, and times out on 9.
+    #[derive(Default)]
+    struct Fake {
+        trials: Vec<Vec<u32>>,
+        rejected: Vec<(u32, String)>,
+        timed_out: Vec<u32>,
+    }
+
+    impl Bisect<u32> for Fake {
+        fn try_changes(&mut self, trial: &[u32]) -> Result<Verdict> {
+            self.trials.push(trial.to_vec());
+            Ok(if trial.contains(&9) {
+                Verdict::TimedOut("check".to_owned())
+            } else if trial.contains(&3) {
+                Verdict::Fail("check".to_owned())
+            } else if trial.contains(&5) && trial.contains(&6) {
+                Verdict::Fail("clippy".to_owned())
+            } else {
+                Verdict::Pass
+            })
+        }
+
+        fn reject_change(&mut self, change: &u32, stage: &str) -> Result<()> {
+            self.rejected.push((*change, stage.to_owned()));
+            Ok(())
+        }
+
+        fn time_out_changes(&mut self, changes: &[u32]) -> Result<()> {
+            self.timed_out.extend(changes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn keeps_largest_passing_subset() {
+        let mut fake = Fake::default();
+        let mut passing = Vec::new();
+        reduce(&mut fake, &mut passing, (1..=8).collect(), "check").unwrap();
+        assert_eq!(passing, vec![1, 2, 4, 5, 7, 8]);
+        // 6 only fails together with 5, so it is left for another try.
+        assert_eq!(fake.rejected, vec![(3, "check".to_owned())]);
+        assert!(fake.trials.contains(&vec![6]));
+        assert!(fake.timed_out.is_empty());
+    }
+
+    #[test]
+    fn rejects_lone_failure_without_checking_again() {
+        let mut fake = Fake::default();
+        let mut passing = Vec::new();
+        reduce(&mut fake, &mut passing, vec![3, 9], "check").unwrap();
+        assert!(passing.is_empty());
+        assert_eq!(fake.rejected, vec![(3, "check".to_owned())]);
+        assert_eq!(fake.timed_out, vec![9]);
+        assert_eq!(fake.trials, vec![vec![3], vec![9]]);
+    }
+}
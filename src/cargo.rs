@@ -1,8 +1,10 @@
 use crate::interrupt::check_interrupted;
 use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
-use std::time::Duration;
-use subprocess::{Exec, Popen, Redirection};
+use std::time::{Duration, Instant};
+use subprocess::{Popen, PopenConfig, Redirection};
 
 /// The result of running a single Cargo command.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -11,6 +13,8 @@ pub enum CargoResult {
     Success,
     /// Cargo failed for some reason.
     Failure,
+    /// Cargo did not finish in time and was killed.
+    Timeout,
 }
 
 impl CargoResult {
@@ -23,22 +27,105 @@ impl CargoResult {
 const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Run one `cargo` subprocess and with appropriate handling of interrupts.
-pub fn run_cargo(cargo_args: &[&str], in_dir: &Path) -> Result<CargoResult> {
-    let cargo_bin = "cargo";
-    let mut child = Exec::cmd(cargo_bin)
-        .stdin(Redirection::None)
-        .stdout(Redirection::None)
-        .stderr(Redirection::Merge)
-        .cwd(in_dir.as_os_str().to_owned())
-        .args(cargo_args)
-        .env("RUSTFLAGS", "-D warnings -A unused-imports")
-        .popen()?;
+pub fn run_cargo(
+    cargo_args: &[&str],
+    in_dir: &Path,
+    timeout: Option<Duration>,
+) -> Result<CargoResult> {
+    run_command("cargo", cargo_args, &[], in_dir, None, timeout)
+}
+
+/// Run `program`, typically cargo, with `env` added to its environment and with
+/// appropriate handling of interrupts.
+///
+/// The program's output goes to `log` if given, and to our own stdout otherwise. If it
+/// runs for longer than `timeout` its whole process group is killed.
+pub fn run_command(
+    program: &str,
+    args: &[&str],
+    env: &[(&str, &str)],
+    in_dir: &Path,
+    log: Option<&File>,
+    timeout: Option<Duration>,
+) -> Result<CargoResult> {
+    let stdout = match log {
+        Some(log) => Redirection::File(log.try_clone()?),
+        None => Redirection::None,
+    };
+    let mut child_env = PopenConfig::current_env();
+    child_env.extend(env.iter().map(|(k, v)| (k.into(), v.into())));
+    let mut argv = vec![program];
+    argv.extend(args.iter());
+    let child = Popen::create(
+        &argv,
+        PopenConfig {
+            stdin: Redirection::None,
+            stdout,
+            stderr: Redirection::Merge,
+            cwd: Some(in_dir.as_os_str().to_owned()),
+            env: Some(child_env),
+            ..setpgid_on_unix()
+        },
+    )
+    .with_context(|| format!("failed to spawn {} {}", program, args.join(" ")))?;
+    wait_for(child, program, args, timeout)
+}
+
+/// Run `program` like [run_command] and return what it printed along with the result.
+///
+/// The output is read through a pipe, so nothing is written to disk.
+pub fn capture_command(
+    program: &str,
+    args: &[&str],
+    in_dir: &Path,
+    timeout: Option<Duration>,
+) -> Result<(CargoResult, String)> {
+    let mut argv = vec![program];
+    argv.extend(args.iter());
+    let mut child = Popen::create(
+        &argv,
+        PopenConfig {
+            stdin: Redirection::None,
+            stdout: Redirection::Pipe,
+            stderr: Redirection::Merge,
+            cwd: Some(in_dir.as_os_str().to_owned()),
+            ..setpgid_on_unix()
+        },
+    )
+    .with_context(|| format!("failed to spawn {} {}", program, args.join(" ")))?;
+    // Read on another thread, so the child doesn't block once the pipe is full.
+    let mut stdout = child.stdout.take().expect("child stdout is piped");
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+    let result = wait_for(child, program, args, timeout)?;
+    let output = reader
+        .join()
+        .map_err(|_| anyhow!("failed to read output of {}", program))?
+        .with_context(|| format!("failed to read output of {}", program))?;
+    Ok((result, output))
+}
+
+/// Wait for `child`, running `program` with `args`, to exit, killing it on an interrupt
+/// or once it has run for longer than `timeout`.
+fn wait_for(
+    mut child: Popen,
+    program: &str,
+    args: &[&str],
+    timeout: Option<Duration>,
+) -> Result<CargoResult> {
+    let started = Instant::now();
     let exit_status = loop {
         if let Err(e) = check_interrupted() {
             terminate_child(child)?;
             return Err(e);
         } else if let Some(status) = child.wait_timeout(WAIT_POLL_INTERVAL)? {
             break status;
+        } else if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            println!("TIMEOUT {} {}", program, args.join(" "));
+            terminate_child(child)?;
+            return Ok(CargoResult::Timeout);
         }
     };
     if exit_status.success() {
@@ -48,8 +135,9 @@ pub fn run_cargo(cargo_args: &[&str], in_dir: &Path) -> Result<CargoResult> {
     }
 }
 
+/// Kill `child` and everything it started, then wait for it to exit.
 #[cfg(unix)]
-fn terminate_child(mut child: Popen) -> Result<()> {
+pub(crate) fn terminate_child(mut child: Popen) -> Result<()> {
     use nix::errno::Errno;
     use nix::sys::signal::{killpg, Signal};
 
@@ -70,7 +158,7 @@ fn terminate_child(mut child: Popen) -> Result<()> {
 }
 
 #[cfg(not(unix))]
-fn terminate_child(mut child: Popen) -> Result<()> {
+pub(crate) fn terminate_child(mut child: Popen) -> Result<()> {
     if let Err(e) = child.terminate() {
         // most likely we raced and it's already gone
         let message = format!("failed to terminate child: {}", e);
@@ -79,3 +167,57 @@ fn terminate_child(mut child: Popen) -> Result<()> {
     child.wait().context("wait for child after kill")?;
     Ok(())
 }
+
+/// Return a config that puts the child in a process group of its own, so that
+/// `terminate_child` can kill everything it starts.
+#[cfg(unix)]
+pub(crate) fn setpgid_on_unix() -> PopenConfig {
+    PopenConfig {
+        setpgid: true,
+        ..Default::default()
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn setpgid_on_unix() -> PopenConfig {
+    Default::default()
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn kill_command_after_timeout() {
+        let started = Instant::now();
+        let result = run_command(
+            "sleep",
+            &["5"],
+            &[],
+            Path::new("."),
+            None,
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap();
+        assert_eq!(result, CargoResult::Timeout);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(
+            run_command("true", &[], &[], Path::new("."), None, None).unwrap(),
+            CargoResult::Success
+        );
+        assert_eq!(
+            run_command("false", &[], &[], Path::new("."), None, None).unwrap(),
+            CargoResult::Failure
+        );
+    }
+
+    #[test]
+    fn capture_output_larger_than_a_pipe() {
+        let (result, output) =
+            capture_command("sh", &["-c", "yes | head -c 200000"], Path::new("."), None).unwrap();
+        assert_eq!(result, CargoResult::Success);
+        assert_eq!(output.len(), 200000);
+    }
+}
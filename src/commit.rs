@@ -0,0 +1,124 @@
+//! Naming the branch accepted mutations are committed to, and describing each commit.
+
+use crate::mutation::Mutation;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Return the name of the branch to commit to at `now`, given the configured
+/// `template`.
+pub fn branch_name(template: &str, now: SystemTime) -> String {
+    template.replace("{date}", &date(now))
+}
+
+/// Format the UTC date of `time` like `2022-03-14`.
+fn date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / 86_400) as i64;
+    // Howard Hinnant's `civil_from_days`, counting in 400 year eras that start on
+    // the 1st of March.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Return the message of a commit of `mutations` made by the run with `seed`.
+///
+/// Every mutation gets a line with its position, item and old and new visibility,
+/// followed by the seed and `trailers`.
+pub fn message(mutations: &[&Mutation], seed: u64, trailers: &[String]) -> String {
+    let items = if mutations.len() == 1 {
+        "item"
+    } else {
+        "items"
+    };
+    let mut message = format!(
+        "[RETYPIST] Narrow the visibility of {} {}\n\n",
+        mutations.len(),
+        items
+    );
+    for mutation in mutations {
+        let _ = writeln!(
+            message,
+            "{}:{}: {}: {} -> {}",
+            mutation.source_file.tree_relative_path().display(),
+            mutation.span().start.line,
+            mutation.item(),
+            visibility(mutation.original()),
+            visibility(mutation.op.replacement())
+        );
+    }
+    let _ = writeln!(message, "\nSeed: {}", seed);
+    for trailer in trailers {
+        let _ = writeln!(message, "{}", trailer);
+    }
+    message
+}
+
+/// Show the visibility written as `text`, which is empty for inherited visibility.
+fn visibility(text: &str) -> &str {
+    if text.is_empty() {
+        "inherited"
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{mutation::MutationOp, source::SourceFile};
+
+    #[test]
+    fn branch_name_has_date() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(branch_name("retypist/{date}", at(0)), "retypist/1970-01-01");
+        assert_eq!(
+            branch_name("retypist/{date}", at(951_782_400)),
+            "retypist/2000-02-29"
+        );
+        assert_eq!(branch_name("fixes", at(1_647_266_400)), "fixes");
+        assert_eq!(date(at(1_647_266_400)), "2022-03-14");
+    }
+
+    #[test]
+    fn message_lists_mutations() {
+        let sf = SourceFile::from_code(
+            Path::new("src/lib.rs"),
+            "pub struct A;\npub(crate) struct B;\n",
+        );
+        let mutations = sf.mutations().unwrap();
+        let chosen: Vec<&Mutation> = mutations
+            .iter()
+            .filter(|m| {
+                (m.item() == "A" && m.op == MutationOp::ToVisCrate)
+                    || (m.item() == "B" && m.op == MutationOp::ToVisInherited)
+            })
+            .collect();
+        assert_eq!(
+            message(&chosen, 7, &["Signed-off-by: A <a@example.com>".to_owned()]),
+            "[RETYPIST] Narrow the visibility of 2 items\n\n\
+             src/lib.rs:1: A: pub -> pub(crate)\n\
+             src/lib.rs:2: B: pub(crate) -> inherited\n\
+             \n\
+             Seed: 7\n\
+             Signed-off-by: A <a@example.com>\n"
+        );
+    }
+}
@@ -0,0 +1,467 @@
+//! Configuration read from `retypist.toml` at the root of the tree.
+//!
+//! ```toml
+//! [check]
+//! command = "cargo"
+//! features = ["serde"]
+//! all-features = false
+//! no-default-features = false
+//! affected-only = true
+//! stages = [
+//!     "check",
+//!     "clippy",
+//!     { name = "bench", args = ["bench", "--no-run"] },
+//! ]
+//!
+//! [check.env]
+//! RUSTFLAGS = "-D warnings"
+//!
+//! [timeouts]
+//! cargo = 1800
+//! git = 60
+//!
+//! [commit]
+//! branch = "retypist/{date}"
+//! author = "Retypist <retypist@example.com>"
+//! trailers = ["Reviewed-by: Jane Doe <jane@example.com>"]
+//! ```
+//!
+//! The deprecated `args = [...]` of `[check]`, from before there were stages, is still
+//! read as a single stage with those arguments.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Name of the configuration file, at the root of the tree.
+pub const CONFIG_FILE: &str = "retypist.toml";
+
+/// All of retypist's configuration.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How to check whether the tree still builds after a mutation.
+    pub check: CheckConfig,
+    /// How long subprocesses may run before they are killed.
+    pub timeouts: Timeouts,
+    /// Where and how accepted mutations are committed.
+    pub commit: CommitConfig,
+}
+
+impl Config {
+    /// Load `retypist.toml` from `root`, or the defaults if there is no such file.
+    pub fn load(root: &Path) -> Result<Config> {
+        let path = root.join(CONFIG_FILE);
+        if !path.is_file() {
+            return Ok(Config::default());
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config from {:?}", path))?;
+        text.parse()
+            .with_context(|| format!("failed to parse config in {:?}", path))
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    /// Parse the text of `retypist.toml`.
+    ///
+    /// The `args` of the check, from before there were stages, are still accepted as a
+    /// single custom stage.
+    fn from_str(text: &str) -> Result<Self> {
+        let mut value: toml::Value = text.parse()?;
+        let check = value.get_mut("check").and_then(toml::Value::as_table_mut);
+        let has_stages = check.as_ref().is_some_and(|c| c.contains_key("stages"));
+        let args = check.and_then(|check| check.remove("args"));
+        let mut config: Config = value.try_into()?;
+        if let Some(args) = args {
+            let args: Vec<String> = args
+                .try_into()
+                .context("`check.args` must be a list of strings")?;
+            if has_stages {
+                return Err(anyhow!(
+                    "`check.args` is deprecated and can't be combined with `check.stages`; \
+                     move the arguments into a stage like {{ name = \"check\", args = [...] }}"
+                ));
+            }
+            eprintln!(
+                "WARNING `check.args` is deprecated; use `check.stages = [{{ name = \"check\", \
+                 args = [...] }}]` instead"
+            );
+            config.check.stages = vec![Stage::custom(args)];
+        }
+        Ok(config)
+    }
+}
+
+/// Limits, in seconds, on how long each kind of subprocess may run; 0 means no limit.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Every cargo command, including each stage of the check.
+    pub cargo: u64,
+    /// Every git command.
+    pub git: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts { cargo: 0, git: 60 }
+    }
+}
+
+impl Timeouts {
+    pub fn cargo(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.cargo)).filter(|t| !t.is_zero())
+    }
+
+    pub fn git(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.git)).filter(|t| !t.is_zero())
+    }
+}
+
+/// Where and how accepted mutations are committed.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommitConfig {
+    /// Branch to commit to, created from the checked out commit if it doesn't exist,
+    /// or with a suffix like `-2` if it lacks some of the checked out commits. `{date}`
+    /// is replaced by today's date, like `2022-03-14`. Empty to commit to whatever
+    /// branch is checked out.
+    pub branch: String,
+    /// Author of the commits, like `Name <email>`, instead of git's configured user.
+    pub author: Option<String>,
+    /// Trailers added to every commit message, like `Signed-off-by: Name <email>`.
+    pub trailers: Vec<String>,
+}
+
+impl Default for CommitConfig {
+    fn default() -> Self {
+        CommitConfig {
+            branch: "retypist/{date}".to_owned(),
+            author: None,
+            trailers: Vec::new(),
+        }
+    }
+}
+
+/// The stages run, in order, to check the tree after applying mutations.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CheckConfig {
+    /// Program every stage runs.
+    pub command: String,
+    /// Stages to run; the first one that fails rejects the mutations.
+    pub stages: Vec<Stage>,
+    /// Features to enable, passed as `--features`.
+    pub features: Vec<String>,
+    /// Pass `--all-features`.
+    pub all_features: bool,
+    /// Pass `--no-default-features`.
+    pub no_default_features: bool,
+    /// Narrow stages that check the whole workspace down to the mutated packages and
+    /// those that depend on them. The whole workspace is still checked before every
+    /// commit.
+    ///
+    /// Only cargo is narrowed, as other commands don't take its `-p` flags.
+    pub affected_only: bool,
+    /// Environment variables to set for every stage, on top of retypist's own
+    /// environment.
+    ///
+    /// By default `RUSTFLAGS` denies warnings, other than unused imports.
+    pub env: BTreeMap<String, String>,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        CheckConfig {
+            command: "cargo".to_owned(),
+            stages: vec![Stage::builtin("check").unwrap()],
+            features: Vec::new(),
+            all_features: false,
+            no_default_features: false,
+            affected_only: true,
+            env: BTreeMap::from([(
+                "RUSTFLAGS".to_owned(),
+                "-D warnings -A unused-imports".to_owned(),
+            )]),
+        }
+    }
+}
+
+impl CheckConfig {
+    /// Return the full argument list of `stage`, with the feature flags added to its
+    /// arguments.
+    ///
+    /// Feature flags go before any `--`, so that they reach cargo and not the tool it runs.
+    pub fn argv(&self, stage: &Stage) -> Vec<String> {
+        let mut flags = Vec::new();
+        if !self.features.is_empty() {
+            flags.push("--features".to_owned());
+            flags.push(self.features.join(","));
+        }
+        if self.all_features {
+            flags.push("--all-features".to_owned());
+        }
+        if self.no_default_features {
+            flags.push("--no-default-features".to_owned());
+        }
+        let mut argv = stage.args.clone();
+        let split = argv.iter().position(|a| a == "--").unwrap_or(argv.len());
+        argv.splice(split..split, flags);
+        argv
+    }
+
+    /// True if stages are to be narrowed to the affected packages: `affected_only` is
+    /// set and the command is cargo.
+    pub fn narrows(&self) -> bool {
+        self.affected_only && Path::new(&self.command).file_stem() == Some("cargo".as_ref())
+    }
+
+    /// Return the environment of `stage`: the shared variables, overridden by the
+    /// stage's own.
+    pub fn env(&self, stage: &Stage) -> BTreeMap<String, String> {
+        let mut env = self.env.clone();
+        env.extend(stage.env.clone());
+        env
+    }
+}
+
+/// One step of checking the tree, such as `cargo clippy`.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "StageSpec")]
+pub struct Stage {
+    /// Name shown when the stage rejects a batch.
+    pub name: String,
+    /// Arguments to the check command, before the feature flags.
+    pub args: Vec<String>,
+    /// Environment variables for this stage only.
+    pub env: BTreeMap<String, String>,
+}
+
+/// Names of the stages that need no configuration, in the order they are best run.
+pub const BUILTIN_STAGES: [&str; 4] = ["check", "clippy", "test", "doc"];
+
+impl Stage {
+    /// Return the built-in stage called `name`, if there is one.
+    pub fn builtin(name: &str) -> Option<Stage> {
+        let (args, env): (&[&str], &[(&str, &str)]) = match name {
+            "check" => (&["check", "--tests", "--workspace"], &[]),
+            "clippy" => (
+                &[
+                    "clippy",
+                    "--workspace",
+                    "--all-targets",
+                    "--",
+                    "-D",
+                    "warnings",
+                ],
+                &[],
+            ),
+            "test" => (&["test", "--workspace"], &[]),
+            "doc" => (
+                &["doc", "--workspace", "--no-deps"],
+                &[("RUSTDOCFLAGS", "-D warnings")],
+            ),
+            _ => return None,
+        };
+        Some(Stage {
+            name: name.to_owned(),
+            args: args.iter().map(|a| (*a).to_owned()).collect(),
+            env: env
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect(),
+        })
+    }
+
+    /// Return a stage that runs the check command with `args`, named after its first
+    /// argument.
+    pub fn custom(args: Vec<String>) -> Stage {
+        Stage {
+            name: args.first().cloned().unwrap_or_else(|| "check".to_owned()),
+            args,
+            env: BTreeMap::new(),
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = anyhow::Error;
+
+    /// Parse the name of a built-in stage, or `name=args` for a custom stage whose
+    /// arguments are separated by whitespace, like `bench=bench --no-run`.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some((name, args)) = s.split_once('=') {
+            if name.is_empty() || args.trim().is_empty() {
+                return Err(anyhow!("expected NAME=ARGS, got {:?}", s));
+            }
+            return Ok(Stage {
+                name: name.to_owned(),
+                args: args.split_whitespace().map(str::to_owned).collect(),
+                env: BTreeMap::new(),
+            });
+        }
+        Stage::builtin(s).ok_or_else(|| {
+            anyhow!(
+                "unknown stage {:?}, expected one of {} or NAME=ARGS",
+                s,
+                BUILTIN_STAGES.join(", ")
+            )
+        })
+    }
+}
+
+/// A stage as written in `retypist.toml`: either the name of a built-in stage or a
+/// table describing it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StageSpec {
+    Builtin(String),
+    Custom {
+        name: String,
+        args: Vec<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+}
+
+impl TryFrom<StageSpec> for Stage {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: StageSpec) -> Result<Self> {
+        match spec {
+            StageSpec::Builtin(name) => Stage::builtin(&name).ok_or_else(|| {
+                anyhow!(
+                    "unknown stage {:?}, expected one of {} or a table",
+                    name,
+                    BUILTIN_STAGES.join(", ")
+                )
+            }),
+            StageSpec::Custom { name, args, env } => Ok(Stage { name, args, env }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [check]
+            command = "cargo"
+            stages = ["check", { name = "lint", args = ["clippy", "--", "-D", "warnings"] }]
+            features = ["a", "b"]
+            no-default-features = true
+
+            [check.env]
+            RUSTFLAGS = ""
+            "#,
+        )
+        .unwrap();
+        let stages = &config.check.stages;
+        assert_eq!(stages.len(), 2);
+        assert_eq!(stages[0], Stage::builtin("check").unwrap());
+        assert_eq!(stages[1].name, "lint");
+        assert_eq!(
+            config.check.argv(&stages[1]),
+            vec![
+                "clippy",
+                "--features",
+                "a,b",
+                "--no-default-features",
+                "--",
+                "-D",
+                "warnings"
+            ]
+        );
+        assert_eq!(config.check.env(&stages[1])["RUSTFLAGS"], "");
+    }
+
+    #[test]
+    fn missing_fields_take_defaults() {
+        let config: Config = toml::from_str("[check]\nall-features = true\n").unwrap();
+        let stages = &config.check.stages;
+        assert_eq!(stages.len(), 1);
+        assert_eq!(
+            config.check.argv(&stages[0]),
+            vec!["check", "--tests", "--workspace", "--all-features"]
+        );
+        assert!(toml::from_str::<Config>("[check]\nfeature = []\n").is_err());
+        assert!(toml::from_str::<Config>("[check]\nstages = [\"lint\"]\n").is_err());
+        assert_eq!(config.timeouts.cargo(), None);
+        assert_eq!(config.timeouts.git(), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn args_are_a_custom_stage() {
+        let config: Config = "[check]\nargs = [\"clippy\", \"--workspace\"]\n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.check.stages,
+            vec![Stage {
+                name: "clippy".to_owned(),
+                args: vec!["clippy".to_owned(), "--workspace".to_owned()],
+                env: BTreeMap::new(),
+            }]
+        );
+        assert!("[check]\nargs = [\"check\"]\nstages = [\"test\"]\n"
+            .parse::<Config>()
+            .is_err());
+        assert!("[check]\nargs = \"check\"\n".parse::<Config>().is_err());
+        assert!("[check]\nfeature = []\n".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn parse_stage_argument() {
+        assert_eq!(
+            "clippy".parse::<Stage>().unwrap(),
+            Stage::builtin("clippy").unwrap()
+        );
+        let bench: Stage = "bench=bench  --no-run".parse().unwrap();
+        assert_eq!(bench.name, "bench");
+        assert_eq!(bench.args, vec!["bench", "--no-run"]);
+        assert!("lint".parse::<Stage>().is_err());
+        assert!("=check".parse::<Stage>().is_err());
+        assert!("lint=".parse::<Stage>().is_err());
+    }
+
+    #[test]
+    fn stage_env_overrides_shared_env() {
+        let check = CheckConfig {
+            env: BTreeMap::from([
+                ("RUSTDOCFLAGS".to_owned(), "".to_owned()),
+                ("RUSTFLAGS".to_owned(), "-D warnings".to_owned()),
+            ]),
+            ..CheckConfig::default()
+        };
+        let env = check.env(&Stage::builtin("doc").unwrap());
+        assert_eq!(env["RUSTDOCFLAGS"], "-D warnings");
+        assert_eq!(env["RUSTFLAGS"], "-D warnings");
+    }
+
+    #[test]
+    fn narrow_only_cargo() {
+        let check = |command: &str, affected_only| CheckConfig {
+            command: command.to_owned(),
+            affected_only,
+            ..CheckConfig::default()
+        };
+        assert!(check("cargo", true).narrows());
+        assert!(check("/usr/local/bin/cargo", true).narrows());
+        assert!(!check("cargo", false).narrows());
+        assert!(!check("make", true).narrows());
+        assert!(!check("sh", true).narrows());
+    }
+}
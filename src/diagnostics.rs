@@ -0,0 +1,181 @@
+//! Attributing compiler errors to the mutations that caused them, from the diagnostics
+//! cargo prints with `--message-format=json`.
+
+use crate::mutation::Mutation;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// One line of cargo's JSON output; only compiler messages are of interest.
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<Diagnostic>,
+}
+
+/// A compiler diagnostic, or one of its notes.
+#[derive(Debug, Deserialize)]
+pub struct Diagnostic {
+    pub message: String,
+    pub level: String,
+    pub spans: Vec<DiagnosticSpan>,
+    pub children: Vec<Diagnostic>,
+}
+
+/// A region of source code a diagnostic points at.
+#[derive(Debug, Deserialize)]
+pub struct DiagnosticSpan {
+    /// Path of the file, relative to the workspace root for workspace members.
+    pub file_name: PathBuf,
+    /// First line of the span, 1-based.
+    pub line_start: usize,
+    /// Last line of the span, 1-based and inclusive.
+    pub line_end: usize,
+    pub is_primary: bool,
+}
+
+impl Diagnostic {
+    /// Return the spans of the diagnostic and of all its notes.
+    fn all_spans(&self) -> Vec<&DiagnosticSpan> {
+        let mut spans: Vec<&DiagnosticSpan> = self.spans.iter().collect();
+        for child in &self.children {
+            spans.extend(child.all_spans());
+        }
+        spans
+    }
+
+    /// Return the names quoted in backticks in the diagnostic and its notes, such as
+    /// `g` in "function `g` is private".
+    fn quoted_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.message.split('`').skip(1).step_by(2).collect();
+        for child in &self.children {
+            names.extend(child.quoted_names());
+        }
+        names
+    }
+}
+
+/// Return the errors in cargo's JSON output, skipping any line that is not a compiler
+/// message.
+pub fn parse_errors(output: &str) -> Vec<Diagnostic> {
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<CargoMessage>(line).ok())
+        .filter(|msg| msg.reason == "compiler-message")
+        .filter_map(|msg| msg.message)
+        .filter(|diag| diag.level == "error")
+        .collect()
+}
+
+/// Return the indices of the `mutations` that `errors` point at, in order.
+///
+/// An error points at a mutation if one of its spans, or one of its notes' spans,
+/// covers the line the mutation changed. Errors that point at no mutation that way,
+/// such as one about a private item used elsewhere, are matched by the item names they
+/// quote instead.
+pub fn culprits(errors: &[Diagnostic], mutations: &[Mutation]) -> Vec<usize> {
+    let mut culprits = Vec::new();
+    for error in errors {
+        let spans = error.all_spans();
+        let mut found: Vec<usize> = mutations
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| {
+                let line = m.span().start.line;
+                spans.iter().any(|span| {
+                    span.file_name.ends_with(m.source_file.tree_relative_path())
+                        && span.line_start <= line
+                        && line <= span.line_end
+                })
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if found.is_empty() {
+            let names = error.quoted_names();
+            found = mutations
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| {
+                    let name = m.item().rsplit("::").next().unwrap_or_default();
+                    let suffix = format!("::{}", name);
+                    names
+                        .iter()
+                        .any(|quoted| *quoted == name || quoted.ends_with(&suffix))
+                })
+                .map(|(i, _)| i)
+                .collect();
+        }
+        culprits.extend(found);
+    }
+    culprits.sort_unstable();
+    culprits.dedup();
+    culprits
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    use super::*;
+    use crate::source::SourceFile;
+
+    fn error(message: &str, file_name: &str, line: usize) -> String {
+        format!(
+            r#"{{"reason":"compiler-message","message":{{"message":"{}","level":"error","spans":[{{"file_name":"{}","line_start":{},"line_end":{},"is_primary":true}}],"children":[]}}}}"#,
+            message, file_name, line, line
+        )
+    }
+
+    #[test]
+    fn attribute_errors_by_span_and_name() {
+        let a = SourceFile::from_code(Path::new("src/a.rs"), "pub fn g() {}\npub fn h() {}\n");
+        let lib = SourceFile::from_code(
+            Path::new("src/lib.rs"),
+            "pub struct S {\n    pub x: u8,\n}\n",
+        );
+        let mutations: Vec<Mutation> = [&a, &lib]
+            .iter()
+            .flat_map(|sf| sf.mutations().unwrap())
+            .filter(|m| m.op == crate::mutation::MutationOp::ToVisSelf)
+            .collect();
+        let names: Vec<&str> = mutations.iter().map(|m| m.item()).collect();
+        assert_eq!(names, vec!["g", "h", "S", "S::x"]);
+
+        let output = [
+            "    Checking demo v0.1.0".to_owned(),
+            r#"{"reason":"compiler-artifact"}"#.to_owned(),
+            error("function `h` is private", "src/main.rs", 3),
+            error("field `x` is never read", "src/lib.rs", 2),
+            error("aborting due to 2 previous errors", "src/main.rs", 9),
+        ]
+        .join("\n");
+        let errors = parse_errors(&output);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(culprits(&errors, &mutations), vec![1, 3]);
+    }
+
+    /// An error cargo printed after `g` in `src/a.rs` was made private, with the long
+    /// explanation of the error code cut short.
+    const RECORDED: &str = r#"{"reason":"compiler-message","package_id":"path+file:///tmp/demo#0.1.0","manifest_path":"/tmp/demo/Cargo.toml","target":{"kind":["lib"],"crate_types":["lib"],"name":"demo","src_path":"/tmp/demo/src/lib.rs","edition":"2021","doc":true,"doctest":true,"test":true},"message":{"rendered":"error[E0603]: function `g` is private\n --> src/lib.rs:6:32\n  |\n6 | pub fn f(foo: &Foo) -> u8 { a::g(); foo.y }\n  |                                ^ private function\n  |\nnote: the function `g` is defined here\n --> src/a.rs:1:1\n  |\n1 | fn g() {}\n  | ^^^^^^\n\n","$message_type":"diagnostic","children":[{"children":[],"code":null,"level":"note","message":"the function `g` is defined here","rendered":null,"spans":[{"byte_end":6,"byte_start":0,"column_end":7,"column_start":1,"expansion":null,"file_name":"src/a.rs","is_primary":true,"label":null,"line_end":1,"line_start":1,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":7,"highlight_start":1,"text":"fn g() {}"}]}]}],"level":"error","message":"function `g` is private","spans":[{"byte_end":99,"byte_start":98,"column_end":33,"column_start":32,"expansion":null,"file_name":"src/lib.rs","is_primary":true,"label":"private function","line_end":6,"line_start":6,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":33,"highlight_start":32,"text":"pub fn f(foo: &Foo) -> u8 { a::g(); foo.y }"}]}],"code":{"code":"E0603","explanation":"A private item was used outside its scope.\n"}}}"#;
+
+    #[test]
+    fn attribute_recorded_error() {
+        let a = SourceFile::from_code(
+            Path::new("src/a.rs"),
+            "pub(crate) fn g() {}\npub enum E { A }\n",
+        );
+        let mutations: Vec<Mutation> = a
+            .mutations()
+            .unwrap()
+            .into_iter()
+            .filter(|m| m.op == crate::mutation::MutationOp::ToVisInherited)
+            .collect();
+        let names: Vec<&str> = mutations.iter().map(|m| m.item()).collect();
+        assert_eq!(names, vec!["g", "E"]);
+
+        let errors = parse_errors(RECORDED);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "function `g` is private");
+        assert_eq!(culprits(&errors, &mutations), vec![0]);
+    }
+}
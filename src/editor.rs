@@ -2,8 +2,14 @@
 
 //! Edit source code.
 
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::ops::Range;
+
 /// A (line, column) position in a source file.
-#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+///
+/// Positions are ordered by line and then by column.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug, Serialize)]
 pub struct LineColumn {
     /// 1-based line number.
     pub line: usize,
@@ -21,17 +27,67 @@ impl From<proc_macro2::LineColumn> for LineColumn {
     }
 }
 
+impl LineColumn {
+    /// Return the byte offset of this position within `code`.
+    ///
+    /// A `\r` before a newline is counted as part of the last column of its line, not as a
+    /// separate column. A position one past the end of a line is the offset of its line
+    /// ending.
+    pub fn byte_offset(&self, code: &str) -> Result<usize> {
+        let out_of_range = || anyhow!("position {}:{} is outside the file", self.line, self.column);
+        if self.line == 0 || self.column == 0 {
+            return Err(out_of_range());
+        }
+        let line_start = if self.line == 1 {
+            0
+        } else {
+            code.match_indices('\n')
+                .nth(self.line - 2)
+                .map(|(i, _)| i + 1)
+                .ok_or_else(out_of_range)?
+        };
+        let line = &code[line_start..];
+        let line = &line[..line.find('\n').unwrap_or(line.len())];
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        line.char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(line.len()))
+            .nth(self.column - 1)
+            .map(|i| line_start + i)
+            .ok_or_else(out_of_range)
+    }
+}
+
 /// A contiguous text span in a file.
 ///
-/// TODO: Perhaps a semi-open range that can represent an empty span would be more general?
+/// The span is semi-open: it covers everything from `start` up to but not including `end`,
+/// so a span whose start and end are equal is empty.
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct Span {
     /// The inclusive position where the span starts.
     pub start: LineColumn,
-    /// The inclusive position where the span ends.
+    /// The exclusive position where the span ends.
     pub end: LineColumn,
 }
 
+impl Span {
+    /// Return the half-open range of bytes the span covers within `code`.
+    pub fn byte_range(&self, code: &str) -> Result<Range<usize>> {
+        let start = self.start.byte_offset(code)?;
+        let end = self.end.byte_offset(code)?;
+        if start > end {
+            return Err(anyhow!(
+                "span {}:{} to {}:{} ends before it starts",
+                self.start.line,
+                self.start.column,
+                self.end.line,
+                self.end.column
+            ));
+        }
+        Ok(start..end)
+    }
+}
+
 impl From<proc_macro2::Span> for Span {
     fn from(s: proc_macro2::Span) -> Self {
         Span {
@@ -50,40 +106,97 @@ impl From<&proc_macro2::Span> for Span {
     }
 }
 
-/// Replace a subregion of text.
+/// A single change to a text: the half-open byte `range` is replaced by `replacement`.
 ///
-/// Returns a copy of `s` with the region between `start` and `end` inclusive replaced by
-/// `replacement`.
-pub(crate) fn replace_region(
-    s: &str,
-    start: &LineColumn,
-    end: &LineColumn,
-    replacement: &str,
-) -> String {
-    // dbg!(start, end);
-    let mut r = String::with_capacity(s.len() + replacement.len());
-    let mut line_no = 1;
-    let mut col_no = 1;
-    for c in s.chars() {
-        if line_no < start.line
-            || line_no > end.line
-            || (line_no == start.line && col_no < start.column)
-            || (line_no == end.line && col_no > end.column)
-        {
-            r.push(c);
-        } else if line_no == start.line && col_no == start.column {
-            r.push_str(replacement);
+/// An empty range is a pure insertion, and an empty replacement is a pure deletion.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct TextEdit {
+    /// The bytes of the original text that are replaced.
+    pub range: Range<usize>,
+    /// The text that takes their place.
+    pub replacement: String,
+}
+
+impl TextEdit {
+    /// Insert `text` before the byte at offset `at`.
+    pub fn insert(at: usize, text: impl Into<String>) -> Self {
+        Self::replace(at..at, text)
+    }
+
+    /// Delete the bytes in `range`.
+    pub fn delete(range: Range<usize>) -> Self {
+        Self::replace(range, "")
+    }
+
+    /// Replace the bytes in `range` with `text`.
+    pub fn replace(range: Range<usize>, text: impl Into<String>) -> Self {
+        TextEdit {
+            range,
+            replacement: text.into(),
         }
-        if c == '\n' {
-            line_no += 1;
-            col_no = 1;
-        } else if c == '\r' {
-            // counts as part of the last column, not a separate column
-        } else {
-            col_no += 1;
+    }
+
+    /// Replace the text covered by `span` in `code` with `text`.
+    pub fn from_span(code: &str, span: impl Into<Span>, text: impl Into<String>) -> Result<Self> {
+        Ok(Self::replace(span.into().byte_range(code)?, text))
+    }
+
+    /// True if the two edits cannot be applied together unambiguously.
+    ///
+    /// That is the case when their ranges share any byte, or when they start at the same
+    /// offset, as for two insertions at the same place.
+    pub fn overlaps(&self, other: &TextEdit) -> bool {
+        self.range.start == other.range.start
+            || (self.range.start < other.range.end && other.range.start < self.range.end)
+    }
+
+    /// Return a copy of `code` with this edit applied.
+    ///
+    /// Panics if the range is not within `code` or does not fall on char boundaries.
+    pub fn apply(&self, code: &str) -> String {
+        let mut r = String::with_capacity(code.len() + self.replacement.len());
+        r.push_str(&code[..self.range.start]);
+        r.push_str(&self.replacement);
+        r.push_str(&code[self.range.end..]);
+        r
+    }
+}
+
+/// Apply several edits to `code` at once.
+///
+/// Every range refers to the original `code`, so edits need not be given in any particular
+/// order. Fails if any two edits overlap or if an edit lies outside `code`.
+pub fn apply_edits(code: &str, edits: &[TextEdit]) -> Result<String> {
+    let mut edits: Vec<&TextEdit> = edits.iter().collect();
+    edits.sort_by_key(|e| (e.range.start, e.range.end));
+    for pair in edits.windows(2) {
+        if pair[0].overlaps(pair[1]) {
+            return Err(anyhow!(
+                "edits of bytes {:?} and {:?} overlap",
+                pair[0].range,
+                pair[1].range
+            ));
+        }
+    }
+    let mut r = String::with_capacity(code.len());
+    let mut copied_to = 0;
+    for edit in edits {
+        if edit.range.start > edit.range.end
+            || !code.is_char_boundary(edit.range.start)
+            || !code.is_char_boundary(edit.range.end)
+            || edit.range.end > code.len()
+        {
+            return Err(anyhow!(
+                "edit of bytes {:?} is not within the text",
+                edit.range
+            ));
         }
+        r.push_str(&code[copied_to..edit.range.start]);
+        r.push_str(&edit.replacement);
+        copied_to = edit.range.end;
     }
-    r
+    r.push_str(&code[copied_to..]);
+    Ok(r)
 }
 
 #[cfg(test)]
@@ -92,19 +205,25 @@ mod test {
 
     use super::*;
 
+    fn span(start: (usize, usize), end: (usize, usize)) -> Span {
+        Span {
+            start: LineColumn {
+                line: start.0,
+                column: start.1,
+            },
+            end: LineColumn {
+                line: end.0,
+                column: end.1,
+            },
+        }
+    }
+
     #[test]
     fn replace_treats_crlf_as_part_of_last_column() {
         let source = "fn foo() {\r\n    wibble();\r\n}\r\n//hey!\r\n";
+        let edit = TextEdit::from_span(source, span((1, 10), (3, 2)), "{}").unwrap();
         assert_eq!(
-            replace_region(
-                source,
-                &LineColumn {
-                    line: 1,
-                    column: 10
-                },
-                &LineColumn { line: 3, column: 2 },
-                "{}\r\n"
-            ),
+            apply_edits(source, &[edit]).unwrap(),
             "fn foo() {}\r\n//hey!\r\n"
         );
     }
@@ -120,16 +239,9 @@ fn foo() {
 const BAR: u32 = 32;
 ";
         // typical multi-line case
+        let edit = TextEdit::from_span(source, span((2, 10), (5, 2)), "{ /* body deleted */ }");
         assert_eq!(
-            replace_region(
-                source,
-                &LineColumn {
-                    line: 2,
-                    column: 10
-                },
-                &LineColumn { line: 5, column: 1 },
-                "{ /* body deleted */ }"
-            ),
+            edit.unwrap().apply(source),
             "
 fn foo() { /* body deleted */ }
 
@@ -138,19 +250,9 @@ const BAR: u32 = 32;
         );
 
         // single-line case
+        let edit = TextEdit::from_span(source, span((7, 18), (7, 20)), "69");
         assert_eq!(
-            replace_region(
-                source,
-                &LineColumn {
-                    line: 7,
-                    column: 18
-                },
-                &LineColumn {
-                    line: 7,
-                    column: 19
-                },
-                "69"
-            ),
+            edit.unwrap().apply(source),
             "
 fn foo() {
     some();
@@ -161,4 +263,65 @@ const BAR: u32 = 69;
 "
         );
     }
+
+    #[test]
+    fn columns_count_chars_not_bytes() {
+        let source = "const É: &str = \"ü\"; pub struct A;\n";
+        let edit = TextEdit::from_span(source, span((1, 22), (1, 25)), "pub(crate)").unwrap();
+        assert_eq!(edit.range, 23..26);
+        assert_eq!(
+            edit.apply(source),
+            "const É: &str = \"ü\"; pub(crate) struct A;\n"
+        );
+
+        let syn_file = syn::parse_str::<syn::File>(source).unwrap();
+        let vis = match &syn_file.items[1] {
+            syn::Item::Struct(s) => &s.vis,
+            _ => unreachable!(),
+        };
+        let pub_token = match vis {
+            syn::Visibility::Public(pv) => pv.pub_token.span,
+            _ => unreachable!(),
+        };
+        assert_eq!(Span::from(pub_token).byte_range(source).unwrap(), 23..26);
+    }
+
+    #[test]
+    fn insert_delete_and_replace_together() {
+        let source = "pub struct A;\npub struct B { pub b: u8 }\n";
+        let edits = [
+            TextEdit::replace(29..32, "pub(crate)"),
+            TextEdit::insert(14, "#[derive(Debug)]\n"),
+            TextEdit::delete(0..4),
+        ];
+        assert_eq!(
+            apply_edits(source, &edits).unwrap(),
+            "struct A;\n#[derive(Debug)]\npub struct B { pub(crate) b: u8 }\n"
+        );
+    }
+
+    #[test]
+    fn overlapping_edits_are_rejected() {
+        let source = "pub struct A;\n";
+        assert!(apply_edits(
+            source,
+            &[TextEdit::delete(0..4), TextEdit::replace(2..6, "x")]
+        )
+        .is_err());
+        assert!(apply_edits(
+            source,
+            &[TextEdit::insert(4, "x"), TextEdit::insert(4, "y")]
+        )
+        .is_err());
+        // Touching, but not overlapping, is fine.
+        assert_eq!(
+            apply_edits(
+                source,
+                &[TextEdit::delete(0..4), TextEdit::replace(4..10, "enum")]
+            )
+            .unwrap(),
+            "enum A;\n"
+        );
+        assert!(apply_edits(source, &[TextEdit::delete(10..20)]).is_err());
+    }
 }
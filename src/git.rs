@@ -1,9 +1,11 @@
+use crate::cargo::{setpgid_on_unix, terminate_child};
 use crate::interrupt::check_interrupted;
 use anyhow::{anyhow, Context, Result};
 use std::borrow::Cow;
 use std::env;
-use std::path::Path;
-use std::time::Duration;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use subprocess::{Popen, PopenConfig, Redirection};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -12,6 +14,8 @@ pub enum GitResult {
     Success,
     /// Cargo failed for some reason.
     Failure,
+    /// Git did not finish in time and was killed.
+    Timeout,
 }
 
 impl GitResult {
@@ -20,15 +24,22 @@ impl GitResult {
     }
 }
 
+/// Return the git program to run: `$GIT` if set, `git` otherwise.
+fn git_bin() -> Cow<'static, str> {
+    env::var("GIT")
+        .map(Cow::from)
+        .unwrap_or(Cow::Borrowed("git"))
+}
+
 /// How frequently to check if cargo finished.
 const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Run one `cargo` subprocess and with appropriate handling of interrupts.
-pub fn run_git(git_args: &[&str], in_dir: &Path) -> Result<GitResult> {
-    let git_bin: Cow<str> = env::var("GIT")
-        .map(Cow::from)
-        .unwrap_or(Cow::Borrowed("git"));
-
+///
+/// If git runs for longer than `timeout`, for example because it is waiting for
+/// credentials, its whole process group is killed.
+pub fn run_git(git_args: &[&str], in_dir: &Path, timeout: Option<Duration>) -> Result<GitResult> {
+    let git_bin = git_bin();
     let mut argv: Vec<&str> = vec![&git_bin];
     argv.extend(git_args.iter());
     let mut child = Popen::create(
@@ -42,12 +53,17 @@ pub fn run_git(git_args: &[&str], in_dir: &Path) -> Result<GitResult> {
         },
     )
     .with_context(|| format!("failed to spawn {} {}", git_bin, git_args.join(" ")))?;
+    let started = Instant::now();
     let exit_status = loop {
         if let Err(e) = check_interrupted() {
             terminate_child(child)?;
             return Err(e);
         } else if let Some(status) = child.wait_timeout(WAIT_POLL_INTERVAL)? {
             break status;
+        } else if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            println!("TIMEOUT {} {}", git_bin, git_args.join(" "));
+            terminate_child(child)?;
+            return Ok(GitResult::Timeout);
         }
     };
     if exit_status.success() {
@@ -57,47 +73,231 @@ pub fn run_git(git_args: &[&str], in_dir: &Path) -> Result<GitResult> {
     }
 }
 
-#[cfg(unix)]
-fn terminate_child(mut child: Popen) -> Result<()> {
-    use nix::errno::Errno;
-    use nix::sys::signal::{killpg, Signal};
-
-    let pid = nix::unistd::Pid::from_raw(child.pid().expect("child has a pid").try_into().unwrap());
-    if let Err(errno) = killpg(pid, Signal::SIGTERM) {
-        if errno == Errno::ESRCH {
-            // most likely we raced and it's already gone
-            return Ok(());
-        } else {
-            let message = format!("failed to terminate child: {}", errno);
-            return Err(anyhow!(message));
+/// Run a quick git command and return what it printed, failing if it exits
+/// unsuccessfully or runs for longer than `timeout`, in which case its whole process
+/// group is killed.
+///
+/// Unlike [run_git] this ignores interrupts, so it can also clean up after one.
+pub fn git_output(git_args: &[&str], in_dir: &Path, timeout: Option<Duration>) -> Result<String> {
+    let git_bin = git_bin();
+    let mut argv: Vec<&str> = vec![&git_bin];
+    argv.extend(git_args.iter());
+    let mut child = Popen::create(
+        &argv,
+        PopenConfig {
+            stdin: Redirection::None,
+            stdout: Redirection::Pipe,
+            stderr: Redirection::Pipe,
+            cwd: Some(in_dir.as_os_str().to_owned()),
+            ..setpgid_on_unix()
+        },
+    )
+    .with_context(|| format!("failed to spawn {} {}", git_bin, git_args.join(" ")))?;
+    let mut communicator = child.communicate_start(None);
+    if let Some(timeout) = timeout {
+        communicator = communicator.limit_time(timeout);
+    }
+    let (stdout, stderr) = match communicator.read_string() {
+        Ok(output) => output,
+        Err(err) if err.error.kind() == ErrorKind::TimedOut => {
+            terminate_child(child)?;
+            return Err(anyhow!("{} {} timed out", git_bin, git_args.join(" ")));
         }
+        Err(err) => {
+            return Err(err.error)
+                .with_context(|| format!("failed to run {} {}", git_bin, git_args.join(" ")))
+        }
+    };
+    // Git closed its output, so it is exiting.
+    if !child.wait()?.success() {
+        return Err(anyhow!(
+            "{} {} failed: {}",
+            git_bin,
+            git_args.join(" "),
+            stderr.unwrap_or_default().trim()
+        ));
     }
-    child
-        .wait()
-        .context("wait for child after terminating pgroup")?;
-    Ok(())
+    Ok(stdout.unwrap_or_default())
 }
 
-#[cfg(not(unix))]
-fn terminate_child(mut child: Popen) -> Result<()> {
-    if let Err(e) = child.terminate() {
-        // most likely we raced and it's already gone
-        let message = format!("failed to terminate child: {}", e);
-        return Err(anyhow!(message));
-    }
-    child.wait().context("wait for child after kill")?;
-    Ok(())
+/// Return the uncommitted changes under `in_dir`, including untracked files, one per
+/// line as printed by `git status --porcelain`.
+pub fn uncommitted_changes(in_dir: &Path, timeout: Option<Duration>) -> Result<Vec<String>> {
+    let status = git_output(&["status", "--porcelain", "--", "."], in_dir, timeout)?;
+    Ok(status.lines().map(str::to_owned).collect())
+}
+
+/// Uncommitted changes stashed away while retypist runs, so that reverting mutations
+/// doesn't destroy them.
+#[derive(Debug)]
+pub struct Stash {
+    root: PathBuf,
+    /// The stash commit, to find the changes should restoring them fail.
+    commit: String,
+    /// How long each git command may run.
+    timeout: Option<Duration>,
 }
 
-#[cfg(unix)]
-fn setpgid_on_unix() -> PopenConfig {
-    PopenConfig {
-        setpgid: true,
-        ..Default::default()
+impl Stash {
+    /// Stash the uncommitted changes under `root`, including untracked files, killing
+    /// git if it runs for longer than `timeout`.
+    pub fn push(root: &Path, timeout: Option<Duration>) -> Result<Stash> {
+        git_output(
+            &[
+                "stash",
+                "push",
+                "--include-untracked",
+                "--message",
+                "retypist: uncommitted changes",
+                "--",
+                ".",
+            ],
+            root,
+            timeout,
+        )?;
+        let commit = git_output(&["rev-parse", "stash@{0}"], root, timeout)?;
+        Ok(Stash {
+            root: root.to_owned(),
+            commit: commit.trim().to_owned(),
+            timeout,
+        })
+    }
+
+    /// Discard whatever retypist left uncommitted under the root and restore the
+    /// stashed changes.
+    pub fn pop(self) -> Result<()> {
+        git_output(&["checkout", "--", "."], &self.root, self.timeout)?;
+        self.restore().with_context(|| {
+            format!(
+                "failed to restore uncommitted changes, which are kept in stash {}; \
+                 resolve any conflicts, then drop it with `git stash drop`",
+                self.commit
+            )
+        })
+    }
+
+    /// Pop the stash, provided it is still the latest one, restoring which changes
+    /// were staged if possible.
+    fn restore(&self) -> Result<()> {
+        let latest = git_output(&["rev-parse", "stash@{0}"], &self.root, self.timeout)?;
+        if latest.trim() != self.commit {
+            return Err(anyhow!("another stash was pushed on top of it"));
+        }
+        if git_output(&["stash", "pop", "--index"], &self.root, self.timeout).is_err() {
+            // The staged changes may no longer apply to the index, for example if they
+            // were committed since, while the changes themselves still do.
+            git_output(&["stash", "pop"], &self.root, self.timeout)?;
+        }
+        Ok(())
     }
 }
 
-#[cfg(not(unix))]
-fn setpgid_on_unix() -> PopenConfig {
-    Default::default()
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::testing::repo_in;
+
+    #[test]
+    fn kill_git_after_timeout() {
+        let started = Instant::now();
+        // The alias runs in a shell, which git waits for.
+        let result = run_git(
+            &["-c", "alias.nap=!sleep 5", "nap"],
+            Path::new("."),
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap();
+        assert_eq!(result, GitResult::Timeout);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let started = Instant::now();
+        let err = git_output(
+            &["-c", "alias.nap=!sleep 5", "nap"],
+            Path::new("."),
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(
+            run_git(&["--version"], Path::new("."), None).unwrap(),
+            GitResult::Success
+        );
+    }
+
+    #[test]
+    fn stash_and_restore_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        repo_in(root, &[("a.rs", "a\n"), ("b.rs", "b\n")]);
+        assert_eq!(
+            uncommitted_changes(root, None).unwrap(),
+            Vec::<String>::new()
+        );
+        std::fs::write(root.join("a.rs"), "a2\n").unwrap();
+        git_output(&["add", "a.rs"], root, None).unwrap();
+        std::fs::write(root.join("b.rs"), "b2\n").unwrap();
+        std::fs::write(root.join("new.rs"), "new\n").unwrap();
+        let changes = vec!["M  a.rs", " M b.rs", "?? new.rs"];
+        assert_eq!(uncommitted_changes(root, None).unwrap(), changes);
+
+        let stash = Stash::push(root, None).unwrap();
+        assert_eq!(
+            uncommitted_changes(root, None).unwrap(),
+            Vec::<String>::new()
+        );
+        // Left behind by retypist.
+        std::fs::write(root.join("b.rs"), "mutated\n").unwrap();
+        stash.pop().unwrap();
+        assert_eq!(uncommitted_changes(root, None).unwrap(), changes);
+        assert_eq!(std::fs::read_to_string(root.join("b.rs")).unwrap(), "b2\n");
+    }
+
+    #[test]
+    fn restore_changes_whose_staged_part_was_committed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        repo_in(root, &[("a.rs", "a\n"), ("b.rs", "b\n"), ("c.rs", "c\n")]);
+        std::fs::write(root.join("a.rs"), "a2\n").unwrap();
+        git_output(&["add", "a.rs"], root, None).unwrap();
+        std::fs::write(root.join("b.rs"), "b2\n").unwrap();
+        let stash = Stash::push(root, None).unwrap();
+        // The staged change, committed by retypist along with one of its own, so it no
+        // longer applies to the index.
+        std::fs::write(root.join("a.rs"), "a2\n").unwrap();
+        std::fs::write(root.join("c.rs"), "c2\n").unwrap();
+        git_output(
+            &["commit", "--quiet", "--all", "--message", "Both"],
+            root,
+            None,
+        )
+        .unwrap();
+
+        stash.pop().unwrap();
+        assert_eq!(uncommitted_changes(root, None).unwrap(), vec![" M b.rs"]);
+        assert_eq!(git_output(&["stash", "list"], root, None).unwrap(), "");
+    }
+
+    #[test]
+    fn keep_stash_if_another_was_pushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        repo_in(root, &[("a.rs", "a\n")]);
+        std::fs::write(root.join("a.rs"), "a2\n").unwrap();
+        let stash = Stash::push(root, None).unwrap();
+        std::fs::write(root.join("a.rs"), "other\n").unwrap();
+        git_output(&["stash", "push", "--quiet"], root, None).unwrap();
+
+        let err = stash.pop().unwrap_err();
+        assert!(format!("{:?}", err).contains("another stash"));
+        assert_eq!(
+            git_output(&["stash", "list"], root, None)
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+        assert_eq!(std::fs::read_to_string(root.join("a.rs")).unwrap(), "a\n");
+    }
 }
@@ -0,0 +1,146 @@
+//! In-memory index of a tree's source files and the mutations possible in them.
+//!
+//! Parsing is the expensive part of finding mutations, so files are parsed once and only
+//! parsed again when their contents change.
+
+use crate::{
+    modules::{mod_declarations, ModDeclaration},
+    mutation::Mutation,
+    source::SourceFile,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Hash `text` with 64-bit FNV-1a.
+///
+/// Unlike the standard library's hashers this gives the same value on every platform and
+/// in every version, so it is safe to store.
+pub fn content_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// A source file as last read, with the mutations found in it.
+#[derive(Debug)]
+struct IndexedFile {
+    /// Hash of the file's code when it was parsed.
+    hash: u64,
+
+    /// All the mutations possible in the file, in the order the visitor found them.
+    mutations: Vec<Mutation>,
+
+    /// The modules the file declares, to find the rest of the tree's files from.
+    modules: Vec<ModDeclaration>,
+}
+
+/// The source files of a tree and their candidate mutations, keyed by tree-relative path.
+#[derive(Debug, Default)]
+pub struct Index {
+    files: BTreeMap<PathBuf, IndexedFile>,
+}
+
+impl Index {
+    /// Bring the entry of `sf` up to date with its current contents, parsing it only if
+    /// it is new or changed. Returns whether it was parsed.
+    pub fn update(&mut self, sf: SourceFile) -> bool {
+        let path = sf.tree_relative_path().to_owned();
+        let hash = content_hash(&sf.code);
+        if self.files.get(&path).is_some_and(|f| f.hash == hash) {
+            return false;
+        }
+        let (mutations, modules) = match syn::parse_str::<syn::File>(&sf.code) {
+            Ok(syn_file) => {
+                let mutations = sf
+                    .mutations_in(&syn_file)
+                    .map_err(|err| eprintln!("error in {}: {}", sf.path().display(), err))
+                    .unwrap_or_default();
+                (mutations, mod_declarations(&syn_file))
+            }
+            Err(err) => {
+                eprintln!("error parsing {}: {}", sf.path().display(), err);
+                (Vec::new(), Vec::new())
+            }
+        };
+        self.files.insert(
+            path,
+            IndexedFile {
+                hash,
+                mutations,
+                modules,
+            },
+        );
+        true
+    }
+
+    /// Drop every file whose path is not in `paths`.
+    pub fn retain(&mut self, paths: &BTreeSet<PathBuf>) {
+        self.files.retain(|path, _| paths.contains(path));
+    }
+
+    /// Return the modules declared in the file at `path`, as of when it was last
+    /// parsed.
+    pub fn modules(&self, path: &Path) -> &[ModDeclaration] {
+        self.files.get(path).map_or(&[], |f| f.modules.as_slice())
+    }
+
+    /// Return every mutation in the index, in a stable order.
+    pub fn mutations(&self) -> impl Iterator<Item = &Mutation> {
+        self.files.values().flat_map(|f| f.mutations.iter())
+    }
+
+    /// Return the mutations of each file that has any.
+    pub fn mutations_by_file(&self) -> impl Iterator<Item = &[Mutation]> {
+        self.files
+            .values()
+            .map(|f| f.mutations.as_slice())
+            .filter(|mutations| !mutations.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn content_hash_is_fnv1a() {
+        assert_eq!(content_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(content_hash("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn update_parses_only_changed_files() {
+        let a = SourceFile::from_code(Path::new("src/a.rs"), "pub struct A;\n");
+        let b = SourceFile::from_code(Path::new("src/b.rs"), "pub struct B;\n");
+        let mut index = Index::default();
+        assert!(index.update(a.clone()));
+        assert!(index.update(b));
+        assert_eq!(index.mutations().count(), 8);
+
+        let b = SourceFile::from_code(Path::new("src/b.rs"), "pub(crate) struct B;\n");
+        assert!(!index.update(a));
+        assert!(index.update(b));
+        assert_eq!(index.mutations().count(), 7);
+
+        index.retain(&BTreeSet::from([PathBuf::from("src/a.rs")]));
+        assert_eq!(index.mutations_by_file().count(), 1);
+        assert_eq!(index.mutations().count(), 4);
+    }
+
+    #[test]
+    fn modules_are_kept_until_the_file_changes() {
+        let lib = SourceFile::from_code(Path::new("src/lib.rs"), "mod a;\nmod b {}\n");
+        let mut index = Index::default();
+        assert!(index.update(lib.clone()));
+        assert!(!index.update(lib));
+        assert_eq!(index.modules(Path::new("src/lib.rs")).len(), 1);
+        assert!(index.modules(Path::new("src/a.rs")).is_empty());
+
+        let lib = SourceFile::from_code(Path::new("src/lib.rs"), "mod a;\nmod b;\n");
+        assert!(index.update(lib));
+        assert_eq!(index.modules(Path::new("src/lib.rs")).len(), 2);
+    }
+}
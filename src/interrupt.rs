@@ -3,22 +3,67 @@
 //! Handle ctrl-c by setting a global atomic and checking it from long-running
 //! operations.
 
+use std::fmt;
+use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 
 static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
+/// Exit status after an interruption, as shells report for SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// The error returned by operations stopped by ctrl-c.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Handle the first ctrl-c by setting the flag, and a second by exiting at once.
 pub fn install_handler() {
-    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))
-        .expect("install ctrl-c handler");
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            eprintln!("interrupted again, exiting without cleaning up");
+            process::exit(EXIT_INTERRUPTED);
+        }
+        eprintln!("interrupted, cleaning up; press ctrl-c again to exit at once");
+    })
+    .expect("install ctrl-c handler");
 }
 
 /// Return an error if the program was interrupted and should exit.
 pub fn check_interrupted() -> Result<()> {
     if INTERRUPTED.load(Ordering::SeqCst) {
-        Err(anyhow!("interrupted"))
+        Err(Interrupted.into())
     } else {
         Ok(())
     }
 }
+
+/// True if `err` or any error it wraps is an [Interrupted].
+pub fn is_interrupted(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<Interrupted>())
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn interrupted_with_context() {
+        let err = Err::<(), _>(anyhow::Error::from(Interrupted))
+            .context("failed to check batch")
+            .unwrap_err();
+        assert!(is_interrupted(&err));
+        assert!(!is_interrupted(&anyhow!("failed to check batch")));
+    }
+}
@@ -1,7 +1,21 @@
+pub mod batch;
+pub mod bisect;
 pub mod cargo;
+pub mod commit;
+pub mod config;
+pub mod diagnostics;
 pub mod editor;
 pub mod git;
+pub mod index;
 pub mod interrupt;
+pub mod metadata;
+pub mod modules;
 pub mod mutation;
+pub mod pipeline;
+pub mod session;
 pub mod source;
+pub mod state;
+#[cfg(test)]
+mod testing;
 pub mod visitor;
+pub mod worker;
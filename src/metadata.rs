@@ -0,0 +1,199 @@
+//! The packages and targets of a workspace, as reported by `cargo metadata`.
+
+use crate::cargo::{capture_command, CargoResult};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The parts of `cargo metadata --format-version 1` that retypist uses.
+#[derive(Debug, Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    /// Ids of the packages that are members of the workspace.
+    pub workspace_members: Vec<String>,
+    pub workspace_root: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub id: String,
+    pub manifest_path: PathBuf,
+    pub targets: Vec<Target>,
+    /// Normal, dev and build dependencies, as declared in the manifest.
+    pub dependencies: Vec<Dependency>,
+}
+
+/// A dependency declared by a package.
+#[derive(Debug, Deserialize)]
+pub struct Dependency {
+    /// Name of the package depended on, even if it is renamed.
+    pub name: String,
+}
+
+/// A library, binary, test, bench, example or build script of a package.
+#[derive(Debug, Deserialize)]
+pub struct Target {
+    pub name: String,
+    /// Kinds of the target, such as `lib`, `bin`, `test` or `custom-build`.
+    pub kind: Vec<String>,
+    /// The target's root source file, such as `src/lib.rs`.
+    pub src_path: PathBuf,
+}
+
+impl Target {
+    /// True if the target is a build script rather than part of the package's code.
+    pub fn is_build_script(&self) -> bool {
+        self.kind.iter().any(|k| k == "custom-build")
+    }
+}
+
+impl Metadata {
+    /// Run `cargo metadata` in `in_dir` and parse its output, killing cargo if it runs
+    /// for longer than `timeout`.
+    ///
+    /// Dependencies are left out, so this needs neither the network nor a lock file.
+    pub fn load(in_dir: &Path, timeout: Option<Duration>) -> Result<Metadata> {
+        let args = ["metadata", "--format-version", "1", "--no-deps"];
+        let (result, output) = capture_command("cargo", &args, in_dir, timeout)?;
+        match result {
+            CargoResult::Success => {}
+            CargoResult::Failure => {
+                return Err(anyhow!(
+                    "cargo metadata failed in {}: {}",
+                    in_dir.display(),
+                    output.trim()
+                ))
+            }
+            CargoResult::Timeout => {
+                return Err(anyhow!("cargo metadata timed out in {}", in_dir.display()))
+            }
+        }
+        // Any warnings cargo printed are mixed in; the metadata is one line of JSON.
+        let json = output
+            .lines()
+            .find(|line| line.starts_with('{'))
+            .unwrap_or_default();
+        Metadata::parse(json)
+    }
+
+    /// Parse the JSON printed by `cargo metadata --format-version 1`.
+    pub fn parse(json: &str) -> Result<Metadata> {
+        serde_json::from_str(json).context("failed to parse cargo metadata")
+    }
+
+    /// Return the packages that are members of the workspace.
+    pub fn members(&self) -> impl Iterator<Item = &Package> {
+        self.packages
+            .iter()
+            .filter(move |p| self.workspace_members.contains(&p.id))
+    }
+
+    /// Return, for every workspace member, the names of the members that depend on it
+    /// directly, including through dev and build dependencies.
+    pub fn dependents(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let mut dependents: BTreeMap<String, BTreeSet<String>> = self
+            .members()
+            .map(|p| (p.name.clone(), BTreeSet::new()))
+            .collect();
+        for package in self.members() {
+            for dependency in &package.dependencies {
+                if let Some(users) = dependents.get_mut(&dependency.name) {
+                    users.insert(package.name.clone());
+                }
+            }
+        }
+        dependents
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::testing::{manifest, write_files};
+
+    #[test]
+    fn parse_metadata() {
+        let metadata = Metadata::parse(
+            r#"{
+                "packages": [
+                    {
+                        "name": "app",
+                        "id": "path+file:///ws/app#0.1.0",
+                        "manifest_path": "/ws/app/Cargo.toml",
+                        "version": "0.1.0",
+                        "dependencies": [
+                            {"name": "core-lib", "rename": "core", "kind": null, "path": "/ws/core"},
+                            {"name": "outside", "kind": "dev"}
+                        ],
+                        "targets": [
+                            {"kind": ["lib"], "name": "app", "src_path": "/ws/app/src/lib.rs"},
+                            {"kind": ["custom-build"], "name": "build-script-build", "src_path": "/ws/app/build.rs"},
+                            {"kind": ["test"], "name": "smoke", "src_path": "/ws/app/tests/smoke.rs"}
+                        ]
+                    },
+                    {
+                        "name": "outside",
+                        "id": "registry+https://example.com#outside@1.0.0",
+                        "manifest_path": "/registry/outside/Cargo.toml",
+                        "dependencies": [],
+                        "targets": []
+                    },
+                    {
+                        "name": "core-lib",
+                        "id": "path+file:///ws/core#0.1.0",
+                        "manifest_path": "/ws/core/Cargo.toml",
+                        "dependencies": [],
+                        "targets": []
+                    }
+                ],
+                "workspace_members": ["path+file:///ws/app#0.1.0", "path+file:///ws/core#0.1.0"],
+                "workspace_root": "/ws"
+            }"#,
+        )
+        .unwrap();
+        let members: Vec<&str> = metadata.members().map(|p| p.name.as_str()).collect();
+        assert_eq!(members, vec!["app", "core-lib"]);
+        assert_eq!(
+            metadata.dependents(),
+            BTreeMap::from([
+                ("app".to_owned(), BTreeSet::new()),
+                ("core-lib".to_owned(), BTreeSet::from(["app".to_owned()])),
+            ])
+        );
+        let build_scripts: Vec<bool> = metadata.packages[0]
+            .targets
+            .iter()
+            .map(Target::is_build_script)
+            .collect();
+        assert_eq!(build_scripts, vec![false, true, false]);
+    }
+
+    #[test]
+    fn load_this_workspace() {
+        let metadata = Metadata::load(Path::new("."), Some(Duration::from_secs(60))).unwrap();
+        let members: Vec<&str> = metadata.members().map(|p| p.name.as_str()).collect();
+        assert_eq!(members, vec!["retypist"]);
+        let empty = tempfile::tempdir().unwrap();
+        assert!(Metadata::load(empty.path(), None).is_err());
+    }
+
+    #[test]
+    fn load_leaves_the_tree_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        write_files(
+            dir.path(),
+            &[
+                ("Cargo.toml", &manifest("demo")),
+                ("src/lib.rs", "pub struct A;\n"),
+            ],
+        );
+        let metadata = Metadata::load(dir.path(), None).unwrap();
+        assert_eq!(metadata.members().count(), 1);
+        assert!(!dir.path().join("target").exists());
+    }
+}
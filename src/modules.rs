@@ -0,0 +1,261 @@
+//! Finding the files of a crate by following its `mod` declarations from the crate's
+//! root file, the way rustc does.
+
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use syn::ext::IdentExt;
+
+/// A file in a crate's module tree.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ModuleFile {
+    pub path: PathBuf,
+    /// Path of the module the file holds, such as `crate::net::tcp`.
+    pub module_path: String,
+}
+
+/// A module declared without a body in a file, as written, before it is looked up
+/// relative to where the file is.
+///
+/// This depends only on the file's code, so it can be kept for as long as that doesn't
+/// change.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ModDeclaration {
+    /// Names of the inline modules the declaration is in, outermost first, each with the
+    /// directory its submodules live in, which is named by `#[path]` or after it.
+    inline: Vec<(String, String)>,
+    name: String,
+    /// Value of the declaration's `#[path]` attribute, if it has one.
+    path_attr: Option<String>,
+}
+
+/// A module declared without a body, whose code is in another file.
+#[derive(Debug, Eq, PartialEq)]
+struct Declaration {
+    module_path: String,
+    /// Files that may hold the module, in the order rustc looks for them, each with
+    /// whether it is a `mod.rs`-like file whose own submodules live next to it.
+    candidates: Vec<(PathBuf, bool)>,
+}
+
+/// Return every file of the crate whose root is `root_file`, starting with the root.
+///
+/// `declared_in` returns the modules declared in each file found, typically by parsing
+/// it. Modules whose file can't be found, or for which `declared_in` fails, are reported
+/// and skipped, along with any modules they declare.
+pub fn module_tree(
+    root_file: &Path,
+    mut declared_in: impl FnMut(&ModuleFile) -> Result<Vec<ModDeclaration>>,
+) -> Vec<ModuleFile> {
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue = vec![Declaration {
+        module_path: "crate".to_owned(),
+        candidates: vec![(root_file.to_owned(), true)],
+    }];
+    while let Some(declaration) = queue.pop() {
+        let (path, mod_rs) = match declaration.candidates.iter().find(|(p, _)| p.is_file()) {
+            Some(found) => found.clone(),
+            None => {
+                eprintln!(
+                    "error finding module {}: none of {:?} exist",
+                    declaration.module_path,
+                    declaration
+                        .candidates
+                        .iter()
+                        .map(|(p, _)| p)
+                        .collect::<Vec<_>>()
+                );
+                continue;
+            }
+        };
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let file = ModuleFile {
+            path,
+            module_path: declaration.module_path,
+        };
+        match declared_in(&file) {
+            Ok(declared) => {
+                // Pop in declaration order.
+                queue.extend(
+                    declared
+                        .iter()
+                        .rev()
+                        .map(|d| resolve(d, &file.path, mod_rs, &file.module_path)),
+                );
+            }
+            Err(err) => eprintln!("error parsing {}: {}", file.path.display(), err),
+        }
+        files.push(file);
+    }
+    files
+}
+
+/// Parse the file at `path` and return the modules it declares.
+pub fn read_declarations(path: &Path) -> Result<Vec<ModDeclaration>> {
+    let code = std::fs::read_to_string(path)?;
+    Ok(mod_declarations(&syn::parse_file(&code)?))
+}
+
+/// Return the modules declared without a body in `file`, in order.
+pub fn mod_declarations(file: &syn::File) -> Vec<ModDeclaration> {
+    let mut declarations = Vec::new();
+    collect(&file.items, &mut Vec::new(), &mut declarations);
+    declarations
+}
+
+/// Add the modules declared in `items`, which are inside the `inline` modules, to
+/// `declarations`.
+fn collect(
+    items: &[syn::Item],
+    inline: &mut Vec<(String, String)>,
+    declarations: &mut Vec<ModDeclaration>,
+) {
+    for item in items {
+        let item_mod = match item {
+            syn::Item::Mod(item_mod) => item_mod,
+            _ => continue,
+        };
+        let name = item_mod.ident.unraw().to_string();
+        let path_attr = path_attribute(&item_mod.attrs);
+        match &item_mod.content {
+            None => declarations.push(ModDeclaration {
+                inline: inline.clone(),
+                name,
+                path_attr,
+            }),
+            Some((_, items)) => {
+                let dir = path_attr.unwrap_or_else(|| name.clone());
+                inline.push((name, dir));
+                collect(items, inline, declarations);
+                inline.pop();
+            }
+        }
+    }
+}
+
+/// Find where the module `declared` in the file at `path`, which holds the module
+/// `module_path`, may be.
+///
+/// Submodules of a `mod.rs`-like file, which includes crate roots and files named by
+/// `#[path]`, live in its directory; those of any other file live in a directory named
+/// after it. A `#[path]` outside any inline module is relative to the file's directory.
+fn resolve(declared: &ModDeclaration, path: &Path, mod_rs: bool, module_path: &str) -> Declaration {
+    let file_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut child_dir = match path.file_stem() {
+        Some(stem) if !mod_rs => file_dir.join(stem),
+        _ => file_dir.to_owned(),
+    };
+    let mut module_path = module_path.to_owned();
+    for (name, dir) in &declared.inline {
+        child_dir.push(dir);
+        module_path = format!("{}::{}", module_path, name);
+    }
+    let name = &declared.name;
+    let candidates = match &declared.path_attr {
+        Some(p) if !declared.inline.is_empty() => vec![(child_dir.join(p), true)],
+        Some(p) => vec![(file_dir.join(p), true)],
+        None => vec![
+            (child_dir.join(format!("{}.rs", name)), false),
+            (child_dir.join(name).join("mod.rs"), true),
+        ],
+    };
+    Declaration {
+        module_path: format!("{}::{}", module_path, name),
+        candidates,
+    }
+}
+
+/// Return the value of a `#[path = "..."]` attribute, if there is one.
+fn path_attribute(attrs: &[syn::Attribute]) -> Option<String> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("path"))
+        .find_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(s),
+                ..
+            })) => Some(s.value()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn declared(path: &str, mod_rs: bool, module_path: &str, code: &str) -> Vec<Declaration> {
+        let file = syn::parse_file(code).unwrap();
+        mod_declarations(&file)
+            .iter()
+            .map(|d| resolve(d, Path::new(path), mod_rs, module_path))
+            .collect()
+    }
+
+    fn decl(module_path: &str, candidates: &[(&str, bool)]) -> Declaration {
+        Declaration {
+            module_path: module_path.to_owned(),
+            candidates: candidates
+                .iter()
+                .map(|(p, mod_rs)| (PathBuf::from(p), *mod_rs))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn crate_root_declarations() {
+        assert_eq!(
+            declared("src/lib.rs", true, "crate", "pub mod net;\nmod r#type;\n"),
+            vec![
+                decl(
+                    "crate::net",
+                    &[("src/net.rs", false), ("src/net/mod.rs", true)]
+                ),
+                decl(
+                    "crate::type",
+                    &[("src/type.rs", false), ("src/type/mod.rs", true)]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_path_and_inline_declarations() {
+        let code = r#"
+            mod tcp;
+            #[path = "udp_impl.rs"]
+            mod udp;
+            mod inline {
+                mod deep;
+                #[path = "other.rs"]
+                mod pathed;
+            }
+            fn f() {}
+        "#;
+        assert_eq!(
+            declared("src/net.rs", false, "crate::net", code),
+            vec![
+                decl(
+                    "crate::net::tcp",
+                    &[("src/net/tcp.rs", false), ("src/net/tcp/mod.rs", true)]
+                ),
+                decl("crate::net::udp", &[("src/udp_impl.rs", true)]),
+                decl(
+                    "crate::net::inline::deep",
+                    &[
+                        ("src/net/inline/deep.rs", false),
+                        ("src/net/inline/deep/mod.rs", true)
+                    ]
+                ),
+                decl(
+                    "crate::net::inline::pathed",
+                    &[("src/net/inline/other.rs", true)]
+                ),
+            ]
+        );
+    }
+}
@@ -1,35 +1,55 @@
 use crate::{
-    editor::{replace_region, Span},
+    editor::{Span, TextEdit},
     source::SourceFile,
 };
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
 
 /// A type of mutation operation that could be applied to a source file.
-#[derive(Debug, Eq, Clone, Copy, PartialEq)]
+#[derive(Debug, Eq, Clone, Copy, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum MutationOp {
     /// Convert a `pub` to a `pub(crate)`
     ToVisCrate,
-    /// Convert a `pub(crate)` to `pub(self)`
+    /// Convert a `pub`, `pub(crate)`, `pub(super)` or `pub(in path)` to `pub(self)`
     ToVisSelf,
-    /// Convert a `pub(crate)` to `pub(super)`
+    /// Convert a `pub` or `pub(crate)` to `pub(super)`
     ToVisSuper,
-    /// Convert a `pub`, `pub(crate)`, `pub(self)`, `pub(super)` to inherited
+    /// Convert a `pub`, `pub(crate)`, `pub(self)`, `pub(super)` or `pub(in path)` to inherited
     ToVisInherited,
 }
 
 impl MutationOp {
     /// Return the text that replaces the body of the mutated span, without the marker comment.
-    fn replacement(&self) -> &'static str {
+    pub(crate) fn replacement(&self) -> &'static str {
         use MutationOp::*;
-        // TODO correct editor so we don't have to add awkward whitespace padding
         match self {
-            ToVisCrate => " pub(crate) ",
-            ToVisSelf => " pub(self) ",
-            ToVisSuper => " pub(super) ",
-            ToVisInherited => " ",
+            ToVisCrate => "pub(crate)",
+            ToVisSelf => "pub(self)",
+            ToVisSuper => "pub(super)",
+            ToVisInherited => "",
         }
     }
 }
 
+/// Identifies a mutation by what it changes rather than by its position, so that it is
+/// recognized again after unrelated edits move it around, but not after the code around
+/// it changes.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct MutationKey {
+    /// Path of the file relative to the tree root.
+    pub path: PathBuf,
+    /// `::`-separated names of the item whose visibility changes.
+    pub item: String,
+    /// The type of change.
+    pub op: MutationOp,
+    /// Hash of the code of the enclosing `struct`, `enum` or `fn`.
+    pub context: u64,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Mutation {
     pub source_file: SourceFile,
@@ -37,26 +57,153 @@ pub struct Mutation {
     /// The mutated textual region.
     span: Span,
 
+    /// The bytes of `source_file` that are replaced.
+    range: Range<usize>,
+
     /// The type of change to apply.
     pub op: MutationOp,
+
+    /// `::`-separated names of the item whose visibility changes, within its file, like
+    /// `Foo::bar` for field `bar` of struct `Foo`.
+    item: String,
+
+    /// Hash of the code of the enclosing `struct`, `enum` or `fn`.
+    context: u64,
 }
 
 impl Mutation {
-    pub fn new(source_file: SourceFile, op: MutationOp, span: Span) -> Mutation {
-        Mutation {
+    /// Fails if `span` does not lie within the source file.
+    pub fn new(
+        source_file: SourceFile,
+        op: MutationOp,
+        span: Span,
+        item: String,
+        context: u64,
+    ) -> Result<Mutation> {
+        let range = span.byte_range(&source_file.code).with_context(|| {
+            format!(
+                "mutation of {} out of bounds of {}",
+                item,
+                source_file.tree_relative_path().display()
+            )
+        })?;
+        Ok(Mutation {
             source_file,
             op,
             span,
+            range,
+            item,
+            context,
+        })
+    }
+
+    /// `::`-separated names of the item whose visibility changes, within its file.
+    pub fn item(&self) -> &str {
+        &self.item
+    }
+
+    /// The mutated textual region.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The text that the mutation replaces, like `pub(crate)`.
+    pub fn original(&self) -> &str {
+        &self.source_file.code[self.range.clone()]
+    }
+
+    /// Identify this mutation independently of exactly where it lies in the file.
+    pub fn key(&self) -> MutationKey {
+        MutationKey {
+            path: self.source_file.tree_relative_path().to_owned(),
+            item: self.item.clone(),
+            op: self.op,
+            context: self.context,
         }
     }
 
+    /// Return the edit that applies this mutation to `source_file`.
+    pub fn edit(&self) -> TextEdit {
+        let mut range = self.range.clone();
+        if self.op.replacement().is_empty() {
+            // Take the whitespace separating the deleted visibility from what follows
+            // along with it.
+            range.end += self.source_file.code[range.end..]
+                .find(|c| c != ' ' && c != '\t')
+                .unwrap_or(0);
+        }
+        TextEdit::replace(range, self.op.replacement())
+    }
+
     /// Return text of the whole file with the mutation applied.
     pub fn mutate(&self) -> String {
-        replace_region(
-            &self.source_file.code,
-            &self.span.start,
-            &self.span.end,
-            self.op.replacement(),
+        self.edit().apply(&self.source_file.code)
+    }
+
+    /// Return a unified diff of the file before and after the mutation.
+    pub fn diff(&self) -> String {
+        let path = self.source_file.tree_relative_path().display();
+        let mutated = self.mutate();
+        TextDiff::from_lines(self.source_file.code.as_str(), mutated.as_str())
+            .unified_diff()
+            .header(&format!("a/{}", path), &format!("b/{}", path))
+            .to_string()
+    }
+}
+
+impl fmt::Display for Mutation {
+    /// Show the file, line, operation and item, like `src/lib.rs:12: ToVisCrate Foo::bar`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {:?} {}",
+            self.source_file.tree_relative_path().display(),
+            self.span.start.line,
+            self.op,
+            self.item
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    use super::*;
+    use crate::editor::LineColumn;
+
+    #[test]
+    fn display_and_diff() {
+        let sf =
+            SourceFile::from_code(Path::new("src/lib.rs"), "struct Foo {\n    pub x: u8,\n}\n");
+        let mutation = sf
+            .mutations()
+            .unwrap()
+            .into_iter()
+            .find(|m| m.op == MutationOp::ToVisInherited)
+            .unwrap();
+        assert_eq!(mutation.to_string(), "src/lib.rs:2: ToVisInherited Foo::x");
+        assert_eq!(
+            mutation.diff(),
+            "--- a/src/lib.rs\n\
+             +++ b/src/lib.rs\n\
+             @@ -1,3 +1,3 @@\n \
+             struct Foo {\n\
+             -    pub x: u8,\n\
+             +    x: u8,\n \
+             }\n"
+        );
+    }
+
+    #[test]
+    fn span_outside_file_is_an_error() {
+        let sf = SourceFile::from_code(Path::new("src/lib.rs"), "pub struct Foo;\n");
+        let span = Span {
+            start: LineColumn { line: 3, column: 1 },
+            end: LineColumn { line: 3, column: 4 },
+        };
+        let result = Mutation::new(sf, MutationOp::ToVisCrate, span, "Foo".to_owned(), 0);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Checking a tree by running the configured stages in order.
+
+use crate::{
+    cargo::{run_command, CargoResult},
+    config::{CheckConfig, Stage},
+    diagnostics::{parse_errors, Diagnostic},
+};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// Where the output of every attempt is logged, relative to the tree root.
+pub const ATTEMPTS_DIR: &str = "target/retypist/attempts";
+
+/// The result of checking a tree with every stage.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    /// Every stage passed.
+    Pass,
+    /// The named stage failed, and no later stage was run.
+    Fail(String),
+    /// The named stage ran out of time and was killed, and no later stage was run.
+    TimedOut(String),
+}
+
+impl Verdict {
+    pub fn pass(&self) -> bool {
+        matches!(self, Verdict::Pass)
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Pass => write!(f, "PASS"),
+            Verdict::Fail(stage) => write!(f, "FAIL {}", stage),
+            Verdict::TimedOut(stage) => write!(f, "TIMEOUT {}", stage),
+        }
+    }
+}
+
+/// The packages a check covers.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Scope {
+    /// Every member of the workspace.
+    Workspace,
+    /// Only the named members, which are the mutated ones and those that depend on them.
+    Packages(BTreeSet<String>),
+}
+
+impl Scope {
+    /// Return the arguments of a stage that covers only this scope, given the full
+    /// argument list `argv` of the stage.
+    ///
+    /// `--workspace`, or its alias `--all`, is replaced with a `-p` for each package. A
+    /// stage that selects no packages, and so covers the whole of a virtual workspace,
+    /// gets the `-p` flags before any `--`. Stages that select packages of their own
+    /// with `-p` or `--package` are left as they are.
+    pub fn narrow(&self, mut argv: Vec<String>) -> Vec<String> {
+        let packages = match self {
+            Scope::Workspace => return argv,
+            Scope::Packages(packages) => packages,
+        };
+        let end = argv.iter().position(|a| a == "--").unwrap_or(argv.len());
+        let flags = packages
+            .iter()
+            .flat_map(|package| ["-p".to_owned(), package.clone()]);
+        if let Some(index) = argv[..end]
+            .iter()
+            .position(|a| a == "--workspace" || a == "--all")
+        {
+            argv.splice(index..index + 1, flags);
+        } else if !argv[..end]
+            .iter()
+            .any(|a| a.starts_with("-p") || a.starts_with("--package"))
+        {
+            argv.splice(end..end, flags);
+        }
+        argv
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Workspace => write!(f, "workspace"),
+            Scope::Packages(packages) => {
+                let packages: Vec<&str> = packages.iter().map(String::as_str).collect();
+                write!(f, "{}", packages.join(" "))
+            }
+        }
+    }
+}
+
+/// Run the stages of `check` over `scope` in `in_dir`, stopping at the first that fails
+/// or runs for longer than `timeout`.
+///
+/// The output of every stage is written to `log`, each one after a line naming the
+/// stage and its command.
+pub fn verify(
+    check: &CheckConfig,
+    scope: &Scope,
+    in_dir: &Path,
+    log: &Path,
+    timeout: Option<Duration>,
+) -> Result<Verdict> {
+    if let Some(parent) = log.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {:?}", parent))?;
+    }
+    let mut log_file =
+        File::create(log).with_context(|| format!("failed to create log {:?}", log))?;
+    for stage in &check.stages {
+        println!("STAGE {} on {}", stage.name, scope);
+        let argv = scope.narrow(check.argv(stage));
+        let args: Vec<&str> = argv.iter().map(String::as_str).collect();
+        let env = check.env(stage);
+        let env: Vec<(&str, &str)> = env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        writeln!(
+            log_file,
+            "==> STAGE {}: {} {}",
+            stage.name,
+            check.command,
+            args.join(" ")
+        )?;
+        match run_command(
+            &check.command,
+            &args,
+            &env,
+            in_dir,
+            Some(&log_file),
+            timeout,
+        )? {
+            CargoResult::Success => {}
+            CargoResult::Failure => return Ok(Verdict::Fail(stage.name.clone())),
+            CargoResult::Timeout => return Ok(Verdict::TimedOut(stage.name.clone())),
+        }
+    }
+    Ok(Verdict::Pass)
+}
+
+/// Run `stage` of `check` over `scope` again in `in_dir` with JSON diagnostics, saving
+/// cargo's output to `output`, and return the errors it reported.
+///
+/// The stage is expected to fail the same way it just did, so cargo mostly replays
+/// diagnostics it already has.
+pub fn diagnose(
+    check: &CheckConfig,
+    stage: &Stage,
+    scope: &Scope,
+    in_dir: &Path,
+    output: &Path,
+    timeout: Option<Duration>,
+) -> Result<Vec<Diagnostic>> {
+    let mut argv = scope.narrow(check.argv(stage));
+    let split = argv.iter().position(|a| a == "--").unwrap_or(argv.len());
+    argv.insert(split, "--message-format=json".to_owned());
+    let args: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let env = check.env(stage);
+    let env: Vec<(&str, &str)> = env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let output_file =
+        File::create(output).with_context(|| format!("failed to create {:?}", output))?;
+    run_command(
+        &check.command,
+        &args,
+        &env,
+        in_dir,
+        Some(&output_file),
+        timeout,
+    )?;
+    let json = std::fs::read_to_string(output)
+        .with_context(|| format!("failed to read diagnostics from {:?}", output))?;
+    Ok(parse_errors(&json))
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| (*a).to_owned()).collect()
+    }
+
+    #[test]
+    fn narrow_to_packages() {
+        let scope = Scope::Packages(BTreeSet::from(["a".to_owned(), "b".to_owned()]));
+        assert_eq!(
+            scope.narrow(args(&["clippy", "--workspace", "--", "--workspace"])),
+            args(&["clippy", "-p", "a", "-p", "b", "--", "--workspace"])
+        );
+        assert_eq!(
+            scope.narrow(args(&["test", "--all", "--", "--ignored"])),
+            args(&["test", "-p", "a", "-p", "b", "--", "--ignored"])
+        );
+        assert_eq!(
+            scope.narrow(args(&["bench", "--no-run"])),
+            args(&["bench", "--no-run", "-p", "a", "-p", "b"])
+        );
+        assert_eq!(
+            scope.narrow(args(&["test", "--package=a", "--", "--all"])),
+            args(&["test", "--package=a", "--", "--all"])
+        );
+        assert_eq!(
+            Scope::Workspace.narrow(args(&["check", "--workspace"])),
+            args(&["check", "--workspace"])
+        );
+    }
+}
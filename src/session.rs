@@ -0,0 +1,1638 @@
+//! One run of retypist over a source tree: choosing batches of mutations, checking them
+//! and committing the ones that pass.
+
+use crate::{
+    batch::Batch,
+    bisect::{reduce, Bisect},
+    cargo::{run_cargo, CargoResult},
+    commit::{branch_name, message},
+    config::{CheckConfig, CommitConfig, Timeouts},
+    diagnostics::culprits,
+    git::{git_output, run_git, GitResult},
+    mutation::{Mutation, MutationKey},
+    pipeline::{diagnose, verify, Scope, Verdict, ATTEMPTS_DIR},
+    source::SourceTree,
+    state::{Outcome, State},
+    worker::{Done, Job, Worker},
+};
+use anyhow::{anyhow, Result};
+use rand::{rngs::StdRng, SeedableRng};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default number of random batches in a row that make no progress before the session
+/// gives up.
+pub const DEFAULT_MAX_IDLE_BATCHES: usize = 100;
+
+/// Default number of mutations the greedy strategy accepts before committing them.
+pub const DEFAULT_COMMIT_EVERY: usize = 10;
+
+/// How batches of mutations are chosen.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Strategy {
+    /// Repeatedly try random batches of several mutations, until no candidates are left
+    /// or no progress is made.
+    Random,
+    /// Try every candidate mutation once, on its own, in a stable order.
+    Exhaustive,
+    /// Apply and check one mutation at a time, keeping each one that passes on top of
+    /// the ones before it, and commit every few accepted mutations.
+    Greedy,
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "exhaustive" => Ok(Strategy::Exhaustive),
+            "greedy" => Ok(Strategy::Greedy),
+            _ => Err(anyhow!(
+                "unknown strategy {:?}, expected random, exhaustive or greedy",
+                s
+            )),
+        }
+    }
+}
+
+/// What happened to the mutations tried during a session.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Number of batches that were checked.
+    pub batches: usize,
+    /// Number of batches that failed the check.
+    pub failed_batches: usize,
+    /// Number of failed batches by the stage that rejected them.
+    pub failed_stages: BTreeMap<String, usize>,
+    /// Number of batches whose check ran out of time.
+    pub timed_out_batches: usize,
+    /// Mutations that were committed.
+    pub accepted: Vec<String>,
+    /// Mutations that are known to break the check on their own.
+    pub rejected: Vec<Rejection>,
+    /// Mutations that were in a batch whose check ran out of time.
+    pub timed_out: Vec<String>,
+    /// Mutations left out of a batch because they were already in it.
+    pub duplicates: usize,
+    /// Mutations left out of a batch because they overlapped one already in it.
+    pub overlaps: usize,
+    /// Every check of the tree, in the order they were run.
+    pub attempts: Vec<Attempt>,
+}
+
+/// One check of the tree with a batch applied.
+#[derive(Debug)]
+pub struct Attempt {
+    pub verdict: Verdict,
+    /// File holding the output of every stage that was run.
+    pub log: PathBuf,
+}
+
+/// A mutation known to break the check on its own.
+#[derive(Debug)]
+pub struct Rejection {
+    pub mutation: String,
+    /// Name of the stage that failed.
+    pub stage: String,
+    /// Log of the attempt that failed.
+    pub log: PathBuf,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "SUMMARY {} batches checked, {} failed; {} mutations accepted, {} rejected",
+            self.batches,
+            self.failed_batches,
+            self.accepted.len(),
+            self.rejected.len()
+        )?;
+        for (stage, failed) in &self.failed_stages {
+            writeln!(f, "stage {} rejected {} batches", stage, failed)?;
+        }
+        if self.timed_out_batches > 0 {
+            writeln!(
+                f,
+                "{} batches timed out, holding {} mutations",
+                self.timed_out_batches,
+                self.timed_out.len()
+            )?;
+        }
+        writeln!(
+            f,
+            "refused {} duplicate and {} overlapping mutations while building batches",
+            self.duplicates, self.overlaps
+        )?;
+        for accepted in &self.accepted {
+            writeln!(f, "ACCEPTED {}", accepted)?;
+        }
+        for timed_out in &self.timed_out {
+            writeln!(f, "TIMEDOUT {}", timed_out)?;
+        }
+        for rejected in &self.rejected {
+            writeln!(
+                f,
+                "REJECTED {} by {}, see {}",
+                rejected.mutation,
+                rejected.stage,
+                rejected.log.display()
+            )?;
+        }
+        for attempt in &self.attempts {
+            writeln!(f, "ATTEMPT {} {}", attempt.log.display(), attempt.verdict)?;
+        }
+        Ok(())
+    }
+}
+
+/// A run of retypist over one tree.
+pub struct Session {
+    tree: SourceTree,
+
+    /// Seed of `rng`, recorded in commit messages so the run can be reproduced.
+    seed: u64,
+
+    /// When the session started, in seconds since the Unix epoch, which sets its logs
+    /// apart from those of earlier runs with the same seed.
+    started: u64,
+
+    /// Source of all random choices made by the session.
+    rng: StdRng,
+
+    /// Outcomes of mutations decided in this run or an earlier one.
+    state: State,
+
+    /// Whether to search a failing batch for the subset of it that passes.
+    bisect: bool,
+
+    /// Give up on the random strategy after this many batches in a row that neither
+    /// accepted nor rejected anything.
+    max_idle_batches: usize,
+
+    /// Number of mutations the greedy strategy accepts before committing them.
+    commit_every: usize,
+
+    /// How to check the tree after applying mutations.
+    check: CheckConfig,
+
+    /// Where and how to commit accepted mutations.
+    commit: CommitConfig,
+
+    /// How long cargo and git may run before they are killed.
+    timeouts: Timeouts,
+
+    /// Whether changes that are about to be committed were checked over only some of
+    /// the workspace's packages.
+    unchecked_workspace: bool,
+
+    /// Number of batches the random strategy checks at the same time, each in a
+    /// worktree of its own.
+    jobs: usize,
+
+    /// Mutations whose check ran out of time in this session, so are not tried again.
+    timed_out: HashSet<MutationKey>,
+
+    /// Batches the greedy strategy accepted, each applied on top of the ones before,
+    /// that are not committed yet.
+    uncommitted: Vec<Batch>,
+
+    summary: Summary,
+}
+
+impl Session {
+    pub fn new(tree: SourceTree, seed: u64) -> Self {
+        Self {
+            tree,
+            seed,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            rng: StdRng::seed_from_u64(seed),
+            state: State::default(),
+            bisect: false,
+            max_idle_batches: DEFAULT_MAX_IDLE_BATCHES,
+            commit_every: DEFAULT_COMMIT_EVERY,
+            check: CheckConfig::default(),
+            commit: CommitConfig::default(),
+            timeouts: Timeouts::default(),
+            unchecked_workspace: false,
+            jobs: 1,
+            timed_out: HashSet::new(),
+            uncommitted: Vec::new(),
+            summary: Summary::default(),
+        }
+    }
+
+    /// When a batch fails, split it up to find and commit the largest subset of it that
+    /// passes, rejecting the mutations that are to blame.
+    pub fn bisect(mut self, bisect: bool) -> Self {
+        self.bisect = bisect;
+        self
+    }
+
+    /// Remember the outcomes of mutations in `state`, skipping those it already knows to
+    /// be rejected.
+    pub fn state(mut self, state: State) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Stop the random strategy after `max_idle_batches` batches in a row that make no
+    /// progress.
+    pub fn max_idle_batches(mut self, max_idle_batches: usize) -> Self {
+        self.max_idle_batches = max_idle_batches;
+        self
+    }
+
+    /// Make the greedy strategy commit after every `commit_every` accepted mutations.
+    pub fn commit_every(mut self, commit_every: usize) -> Self {
+        self.commit_every = commit_every.max(1);
+        self
+    }
+
+    /// Check the tree after applying mutations by running the stages of `check`.
+    pub fn check(mut self, check: CheckConfig) -> Self {
+        self.check = check;
+        self
+    }
+
+    /// Commit accepted mutations as `commit` says.
+    pub fn commit(mut self, commit: CommitConfig) -> Self {
+        self.commit = commit;
+        self
+    }
+
+    /// Kill cargo and git when they run for longer than `timeouts` allow.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Check up to `jobs` random batches at the same time.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    pub fn tree(&self) -> &SourceTree {
+        &self.tree
+    }
+
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// Choose a random batch of mutations not yet known to be rejected, without applying
+    /// it.
+    ///
+    /// The batch is empty if no candidates are left.
+    pub fn sample(&mut self) -> Result<Batch> {
+        let state = &self.state;
+        let timed_out = &self.timed_out;
+        self.tree.mutation(&mut self.rng, |m| {
+            let key = m.key();
+            state.is_rejected(&key) || timed_out.contains(&key)
+        })
+    }
+
+    /// True if `mutation` is neither known to be rejected nor timed out in this session.
+    fn untried(&self, mutation: &Mutation) -> bool {
+        let key = mutation.key();
+        !self.state.is_rejected(&key) && !self.timed_out.contains(&key)
+    }
+
+    /// Choose, check and commit mutations according to `strategy`, on the configured
+    /// branch if there is one.
+    pub fn run(&mut self, strategy: Strategy) -> Result<()> {
+        self.revert()?;
+        let original = self.switch_branch()?;
+        let result = self.run_strategy(strategy);
+        if result.is_err() {
+            // Don't leave the batch in progress behind, even after an interrupt, which
+            // stops [Session::revert].
+            if let Err(err) = git_output(
+                &["checkout", "--", "."],
+                self.tree.root(),
+                self.timeouts.git(),
+            ) {
+                eprintln!("error reverting: {:?}", err);
+            } else if let Err(err) = self.commit_stopped() {
+                eprintln!("error committing accepted mutations: {:?}", err);
+            }
+        }
+        match original {
+            Some(original) => self.switch_back(&original).and(result),
+            None => result,
+        }
+    }
+
+    fn run_strategy(&mut self, strategy: Strategy) -> Result<()> {
+        match strategy {
+            Strategy::Random if self.jobs > 1 => self.run_parallel(),
+            _ if self.jobs > 1 => Err(anyhow!(
+                "only the random strategy can check batches in parallel"
+            )),
+            Strategy::Random => self.run_random(),
+            Strategy::Exhaustive => self.run_exhaustive(),
+            Strategy::Greedy => self.run_greedy(),
+        }
+    }
+
+    fn run_random(&mut self) -> Result<()> {
+        let mut idle_batches = 0;
+        loop {
+            if idle_batches >= self.max_idle_batches {
+                println!(
+                    "DONE no mutations accepted or rejected in the last {} batches",
+                    idle_batches
+                );
+                return Ok(());
+            }
+            let batch = self.sample()?;
+            if batch.is_empty() {
+                println!("DONE no undecided mutations left");
+                return Ok(());
+            }
+            let decided_before = self.state.len();
+            self.summary.duplicates += batch.duplicates();
+            self.summary.overlaps += batch.overlaps();
+            println!(
+                "BATCH {} mutations, refused {} duplicate and {} overlapping ({} and {} in total)",
+                batch.len(),
+                batch.duplicates(),
+                batch.overlaps(),
+                self.summary.duplicates,
+                self.summary.overlaps,
+            );
+            self.try_batch(&batch)?;
+            if self.state.len() == decided_before {
+                idle_batches += 1;
+            } else {
+                idle_batches = 0;
+            }
+        }
+    }
+
+    /// Run the random strategy with a worker per job, each checking batches in a
+    /// worktree of its own.
+    fn run_parallel(&mut self) -> Result<()> {
+        let root = self.tree.root().to_owned();
+        let prefix = git_output(&["rev-parse", "--show-prefix"], &root, self.timeouts.git())?;
+        let (done_tx, done_rx) = channel();
+        let mut workers = Vec::with_capacity(self.jobs);
+        let mut result = Ok(());
+        for index in 0..self.jobs {
+            match Worker::spawn(
+                index,
+                &root,
+                prefix.trim(),
+                self.check.clone(),
+                self.timeouts,
+                done_tx.clone(),
+            ) {
+                Ok(worker) => workers.push(worker),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        drop(done_tx);
+        if result.is_ok() {
+            result = self.coordinate(&workers, &done_rx);
+        }
+        for worker in workers {
+            if let Err(err) = worker.finish(&root, self.timeouts.git()) {
+                eprintln!("error removing worktree: {:?}", err);
+            }
+        }
+        result
+    }
+
+    /// Hand random batches to idle `workers` and commit those that pass, one at a time,
+    /// until no candidates are left or no progress is made.
+    ///
+    /// A batch that passed on top of a commit that is no longer the latest is checked
+    /// again on top of the latest one, or dropped if the files it touches have changed
+    /// since it was sampled. The errors of a failing batch are attributed to its
+    /// mutations in the tree itself, as [Session::attribute] does for a serial run.
+    fn coordinate(&mut self, workers: &[Worker], done: &Receiver<Done>) -> Result<()> {
+        let mut idle: Vec<usize> = (0..workers.len()).rev().collect();
+        let mut recheck: VecDeque<Batch> = VecDeque::new();
+        // Items with a mutation being checked or waiting in `recheck`, so that no two
+        // workers try the same one.
+        let mut in_flight: HashSet<(PathBuf, String)> = HashSet::new();
+        let mut idle_batches = 0;
+        let mut stopping = false;
+        loop {
+            while let Some(&worker) = idle.last() {
+                let batch = match recheck.pop_front() {
+                    // Checking it would undo the commits since.
+                    Some(batch) if !batch.is_current()? => {
+                        println!(
+                            "STALE {} mutations of files changed since they were sampled",
+                            batch.len()
+                        );
+                        for mutation in batch.iter() {
+                            in_flight.remove(&item_of(mutation));
+                        }
+                        continue;
+                    }
+                    Some(batch) => batch,
+                    None if stopping => break,
+                    None => {
+                        if idle_batches >= self.max_idle_batches {
+                            println!(
+                                "DONE no mutations accepted or rejected in the last {} batches",
+                                idle_batches
+                            );
+                            stopping = true;
+                            break;
+                        }
+                        let batch = self.sample_excluding(&in_flight)?;
+                        if batch.is_empty() {
+                            if idle.len() == workers.len() {
+                                println!("DONE no undecided mutations left");
+                                stopping = true;
+                            }
+                            break;
+                        }
+                        self.summary.duplicates += batch.duplicates();
+                        self.summary.overlaps += batch.overlaps();
+                        batch
+                    }
+                };
+                idle.pop();
+                in_flight.extend(batch.iter().map(item_of));
+                let base = git_output(
+                    &["rev-parse", "HEAD"],
+                    self.tree.root(),
+                    self.timeouts.git(),
+                )?;
+                let scope = self.scope(batch.iter());
+                let log = self.next_attempt();
+                println!(
+                    "BATCH {} mutations on worker {}, attempt {}",
+                    batch.len(),
+                    worker,
+                    self.summary.batches
+                );
+                workers[worker].send(Job {
+                    attempt: self.summary.batches,
+                    base: base.trim().to_owned(),
+                    batch,
+                    scope,
+                    log,
+                })?;
+            }
+            if idle.len() == workers.len() {
+                return Ok(());
+            }
+            let Done {
+                worker,
+                job,
+                verdict,
+            } = done.recv().map_err(|_| anyhow!("every worker stopped"))?;
+            idle.push(worker);
+            for mutation in job.batch.iter() {
+                in_flight.remove(&item_of(mutation));
+            }
+            let verdict = verdict?;
+            println!("ATTEMPT {} {}", job.attempt, verdict);
+            self.record_attempt(&verdict, job.log);
+            let decided_before = self.state.len();
+            let batch = job.batch;
+            match verdict {
+                Verdict::Pass => {
+                    let head = git_output(
+                        &["rev-parse", "HEAD"],
+                        self.tree.root(),
+                        self.timeouts.git(),
+                    )?;
+                    if head.trim() == job.base {
+                        batch.apply()?;
+                        self.unchecked_workspace = job.scope != Scope::Workspace;
+                        self.accept(&batch)?;
+                    } else {
+                        println!("RECHECK attempt {} on top of newer commits", job.attempt);
+                        in_flight.extend(batch.iter().map(item_of));
+                        recheck.push_back(batch);
+                        // Not idle: it is decided once checked again.
+                        continue;
+                    }
+                }
+                Verdict::Fail(stage) => {
+                    if batch.len() == 1 {
+                        self.reject(batch.iter().next().unwrap(), &stage)?;
+                    } else if batch.is_current()? {
+                        // Find the culprits here, as a serial run would, while the
+                        // workers go on with other batches.
+                        batch.apply()?;
+                        self.attribute(batch.iter().cloned().collect(), stage)?;
+                    } else {
+                        println!(
+                            "STALE attempt {} of files changed since it was sampled",
+                            job.attempt
+                        );
+                    }
+                }
+                Verdict::TimedOut(_) => self.time_out(&batch)?,
+            }
+            if self.state.len() == decided_before {
+                idle_batches += 1;
+            } else {
+                idle_batches = 0;
+            }
+        }
+    }
+
+    /// Like [Session::sample], but leaving out mutations of the items in `in_flight`.
+    fn sample_excluding(&mut self, in_flight: &HashSet<(PathBuf, String)>) -> Result<Batch> {
+        let state = &self.state;
+        let timed_out = &self.timed_out;
+        self.tree.mutation(&mut self.rng, |m| {
+            let key = m.key();
+            state.is_rejected(&key) || timed_out.contains(&key) || in_flight.contains(&item_of(m))
+        })
+    }
+
+    fn run_exhaustive(&mut self) -> Result<()> {
+        loop {
+            let next = self.tree.mutations().find(|m| self.untried(m)).cloned();
+            let mutation = match next {
+                Some(mutation) => mutation,
+                None => {
+                    println!("DONE every mutation was tried");
+                    return Ok(());
+                }
+            };
+            println!("MUTATION {}", mutation);
+            self.try_batch(&Batch::single(mutation))?;
+        }
+    }
+
+    fn run_greedy(&mut self) -> Result<()> {
+        loop {
+            let next = self.tree.mutations().find(|m| self.untried(m)).cloned();
+            let mutation = match next {
+                Some(mutation) => mutation,
+                None if self.uncommitted.is_empty() => break,
+                None => {
+                    // Should the commit be refused, its mutations are tried again.
+                    self.commit_greedy()?;
+                    self.tree.refresh();
+                    continue;
+                }
+            };
+            println!("MUTATION {}", mutation);
+            let batch = Batch::single(mutation);
+            match self.check_batch(&batch)? {
+                Verdict::Pass => {
+                    println!("PASS");
+                    // Remembered right away, so that it isn't lost if the run stops
+                    // before the next commit.
+                    for mutation in batch.iter() {
+                        self.state.record(mutation.key(), Outcome::Accepted)?;
+                    }
+                    self.uncommitted.push(batch);
+                    if self.uncommitted.len() >= self.commit_every {
+                        self.commit_greedy()?;
+                    }
+                    self.tree.refresh();
+                }
+                Verdict::Fail(stage) => {
+                    println!("FAIL {}", stage);
+                    // Only undo this mutation: earlier accepted ones may not be
+                    // committed yet.
+                    batch.revert()?;
+                    self.reject(batch.iter().next().unwrap(), &stage)?;
+                }
+                Verdict::TimedOut(stage) => {
+                    println!("TIMEOUT {}", stage);
+                    batch.revert()?;
+                    self.time_out(&batch)?;
+                }
+            }
+        }
+        println!("DONE every mutation was tried");
+        Ok(())
+    }
+
+    /// Commit the uncommitted mutations of the greedy strategy and add them to the
+    /// summary.
+    ///
+    /// If the commit is refused, the tree is reverted to the last commit, where the
+    /// mutations are found again once it is refreshed, to be tried one at a time over
+    /// the whole workspace.
+    fn commit_greedy(&mut self) -> Result<()> {
+        // Kept until committed, in case the run stops while committing them.
+        let uncommitted = self.uncommitted.clone();
+        if self.commit_changes(&uncommitted)? {
+            let accepted = uncommitted.iter().flat_map(Batch::iter);
+            self.summary
+                .accepted
+                .extend(accepted.map(|mutation| mutation.to_string()));
+        } else {
+            println!("REQUEUE {} mutations", uncommitted.len());
+        }
+        self.uncommitted.clear();
+        Ok(())
+    }
+
+    /// Commit the mutations the greedy strategy accepted but had not committed when the
+    /// run stopped, once the tree is reverted.
+    ///
+    /// This works after an interrupt too, so they are committed as they are, without
+    /// formatting or checking them again. If they were only checked over some of the
+    /// workspace's packages, they are left to the state, which records them as accepted.
+    fn commit_stopped(&mut self) -> Result<()> {
+        let uncommitted = std::mem::take(&mut self.uncommitted);
+        if uncommitted.is_empty() {
+            return Ok(());
+        }
+        if self.unchecked_workspace {
+            println!(
+                "REVERTED {} accepted mutations not checked over the whole workspace",
+                uncommitted.len()
+            );
+            return Ok(());
+        }
+        for batch in &uncommitted {
+            batch.apply()?;
+        }
+        let mutations: Vec<&Mutation> = uncommitted.iter().flat_map(Batch::iter).collect();
+        let args = self.commit_args(&mutations);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        // Unlike [Session::git], this still works after an interrupt.
+        git_output(&args, self.tree.root(), self.timeouts.git())?;
+        println!("COMMITTED {} accepted mutations", mutations.len());
+        self.summary
+            .accepted
+            .extend(mutations.iter().map(|mutation| mutation.to_string()));
+        Ok(())
+    }
+
+    /// Apply `batch` and check the tree. Commit the batch if the check passes and revert
+    /// it otherwise.
+    fn try_batch(&mut self, batch: &Batch) -> Result<Verdict> {
+        let verdict = self.check_batch(batch)?;
+        match &verdict {
+            Verdict::Pass => {
+                println!("PASS");
+                self.accept(batch)?;
+            }
+            Verdict::Fail(stage) => {
+                println!("FAIL {}", stage);
+                if batch.len() == 1 {
+                    self.revert()?;
+                    self.reject(batch.iter().next().unwrap(), stage)?;
+                } else {
+                    self.attribute(batch.iter().cloned().collect(), stage.clone())?;
+                }
+            }
+            Verdict::TimedOut(stage) => {
+                println!("TIMEOUT {}", stage);
+                self.revert()?;
+                self.time_out(batch)?;
+            }
+        }
+        Ok(verdict)
+    }
+
+    /// Find the mutations of the applied and failing `candidates` that the compiler's
+    /// errors point at, revert only those and check the rest again, committing whatever
+    /// passes.
+    ///
+    /// Each culprit is then checked on its own and rejected if it still fails, unless
+    /// bisection is enabled, which instead searches them for mutations that pass on top
+    /// of the rest. Bisection is the fallback too when the errors can't be attributed.
+    fn attribute(&mut self, mut candidates: Vec<Mutation>, mut stage: String) -> Result<()> {
+        loop {
+            let culprits = self.diagnose(&candidates, &stage)?;
+            self.revert()?;
+            if culprits.is_empty() || culprits.len() == candidates.len() {
+                if self.bisect {
+                    self.bisect_batch(&candidates.into_iter().collect(), &stage)?;
+                }
+                return Ok(());
+            }
+            println!(
+                "ATTRIBUTED {} of {} mutations",
+                culprits.len(),
+                candidates.len()
+            );
+            let (culprits, rest): (Vec<_>, Vec<_>) = candidates
+                .into_iter()
+                .enumerate()
+                .partition(|(i, _)| culprits.contains(i));
+            let culprits: Vec<Mutation> = culprits.into_iter().map(|(_, m)| m).collect();
+            let mut rest: Batch = rest.into_iter().map(|(_, m)| m).collect();
+            match self.check_batch(&rest)? {
+                Verdict::Pass => {
+                    println!("PASS");
+                    self.revert()?;
+                    if self.bisect {
+                        // The culprits fail on top of the rest, but may not all be to
+                        // blame.
+                        self.reduce(&mut rest, culprits, &stage)?;
+                    } else {
+                        for culprit in culprits {
+                            self.check_alone(culprit)?;
+                        }
+                    }
+                    rest.apply()?;
+                    self.accept(&rest)?;
+                    return Ok(());
+                }
+                Verdict::Fail(rest_stage) => {
+                    println!("FAIL {}", rest_stage);
+                    if rest.len() == 1 {
+                        self.revert()?;
+                        self.reject(rest.iter().next().unwrap(), &rest_stage)?;
+                        return Ok(());
+                    }
+                    candidates = rest.iter().cloned().collect();
+                    stage = rest_stage;
+                }
+                Verdict::TimedOut(rest_stage) => {
+                    println!("TIMEOUT {}", rest_stage);
+                    self.revert()?;
+                    return self.time_out(&rest);
+                }
+            }
+        }
+    }
+
+    /// Check `culprit`, blamed for failing along with other mutations, on its own and
+    /// reject it if it still fails. If it passes it is left to be tried again.
+    fn check_alone(&mut self, culprit: Mutation) -> Result<()> {
+        println!("ALONE {}", culprit);
+        let batch: Batch = std::iter::once(culprit.clone()).collect();
+        let verdict = self.check_batch(&batch)?;
+        self.revert()?;
+        match verdict {
+            Verdict::Pass => {
+                println!("UNDECIDED {} fails only with other mutations", culprit);
+                Ok(())
+            }
+            Verdict::Fail(stage) => self.reject(&culprit, &stage),
+            Verdict::TimedOut(_) => self.time_out(&batch),
+        }
+    }
+
+    /// Return the indices of the applied `candidates` blamed by the errors of `stage`.
+    fn diagnose(&self, candidates: &[Mutation], stage: &str) -> Result<Vec<usize>> {
+        let stage = match self.check.stages.iter().find(|s| s.name == stage) {
+            Some(stage) => stage,
+            None => return Ok(Vec::new()),
+        };
+        let output = self.attempt_path("json");
+        let errors = diagnose(
+            &self.check,
+            stage,
+            &self.scope(candidates),
+            self.tree.root(),
+            &output,
+            self.timeouts.cargo(),
+        )?;
+        Ok(culprits(&errors, candidates))
+    }
+
+    /// Search `batch`, which failed `stage`, for the largest subset that passes, in the
+    /// style of delta debugging, and commit it.
+    fn bisect_batch(&mut self, batch: &Batch, stage: &str) -> Result<()> {
+        let mut passing = Batch::default();
+        self.reduce(&mut passing, batch.iter().cloned().collect(), stage)?;
+        if !passing.is_empty() {
+            // Every mutation was added to `passing` after a check of exactly that set
+            // passed, so there's no need to check it again.
+            passing.apply()?;
+            self.accept(&passing)?;
+        }
+        Ok(())
+    }
+
+    /// Given that `passing` passes but `passing` plus `failing` does not, failing
+    /// `stage`, move as many of `failing` into `passing` as possible and reject those
+    /// that fail on their own.
+    fn reduce(&mut self, passing: &mut Batch, failing: Vec<Mutation>, stage: &str) -> Result<()> {
+        let mut accepted: Vec<Mutation> = passing.iter().cloned().collect();
+        reduce(self, &mut accepted, failing, stage)?;
+        *passing = accepted.into_iter().collect();
+        Ok(())
+    }
+
+    /// Apply `batch` to the tree and check it, leaving the batch applied.
+    ///
+    /// The check's output is logged to a file named after the start of the session, the
+    /// seed and the number of the attempt, so logs from earlier runs are kept.
+    fn check_batch(&mut self, batch: &Batch) -> Result<Verdict> {
+        batch.apply()?;
+        let scope = self.scope(batch.iter());
+        let log = self.next_attempt();
+        let verdict = verify(
+            &self.check,
+            &scope,
+            self.tree.root(),
+            &log,
+            self.timeouts.cargo(),
+        )?;
+        self.record_attempt(&verdict, log);
+        if verdict.pass() && scope != Scope::Workspace {
+            self.unchecked_workspace = true;
+        }
+        Ok(verdict)
+    }
+
+    /// Return the packages to check `mutations` over.
+    fn scope<'a>(&self, mutations: impl IntoIterator<Item = &'a Mutation>) -> Scope {
+        if self.check.narrows() {
+            self.tree.scope(mutations)
+        } else {
+            Scope::Workspace
+        }
+    }
+
+    /// Count a new attempt and return the file to log its check to.
+    fn next_attempt(&mut self) -> PathBuf {
+        self.summary.batches += 1;
+        self.attempt_path("log")
+    }
+
+    /// Return the file with `extension` for the latest attempt, named after the start of
+    /// the session, the seed and the number of the attempt.
+    fn attempt_path(&self, extension: &str) -> PathBuf {
+        self.tree.root().join(ATTEMPTS_DIR).join(format!(
+            "{}-{}-{}.{}",
+            self.started, self.seed, self.summary.batches, extension
+        ))
+    }
+
+    /// Add the result of the attempt logged to `log` to the summary.
+    fn record_attempt(&mut self, verdict: &Verdict, log: PathBuf) {
+        match verdict {
+            Verdict::Pass => {}
+            Verdict::Fail(stage) => {
+                println!("LOG {}", log.display());
+                self.summary.failed_batches += 1;
+                *self.summary.failed_stages.entry(stage.clone()).or_default() += 1;
+            }
+            Verdict::TimedOut(_) => {
+                println!("LOG {}", log.display());
+                self.summary.timed_out_batches += 1;
+            }
+        }
+        self.summary.attempts.push(Attempt {
+            verdict: verdict.clone(),
+            log,
+        });
+    }
+
+    /// Commit the applied `batch`.
+    fn accept(&mut self, batch: &Batch) -> Result<()> {
+        let committed = self.commit_changes(std::slice::from_ref(batch))?;
+        self.tree.refresh();
+        if !committed {
+            // Reverted, and left for another try.
+            return Ok(());
+        }
+        self.record_accepted(batch)
+    }
+
+    /// Record that every mutation of `batch` passed the check.
+    fn record_accepted(&mut self, batch: &Batch) -> Result<()> {
+        for mutation in batch.iter() {
+            self.state.record(mutation.key(), Outcome::Accepted)?;
+            self.summary.accepted.push(mutation.to_string());
+        }
+        Ok(())
+    }
+
+    /// Record that `mutation` breaks `stage` of the check on its own, as shown by the
+    /// latest attempt.
+    fn reject(&mut self, mutation: &Mutation, stage: &str) -> Result<()> {
+        self.state.record(mutation.key(), Outcome::Rejected)?;
+        let log = self
+            .summary
+            .attempts
+            .last()
+            .map(|attempt| attempt.log.clone())
+            .unwrap_or_default();
+        self.summary.rejected.push(Rejection {
+            mutation: mutation.to_string(),
+            stage: stage.to_owned(),
+            log,
+        });
+        Ok(())
+    }
+
+    /// Record that the check of a batch holding `mutations` ran out of time, so that
+    /// they are not tried again in this session.
+    fn time_out(&mut self, mutations: &Batch) -> Result<()> {
+        for mutation in mutations.iter() {
+            self.state.record(mutation.key(), Outcome::TimedOut)?;
+            self.timed_out.insert(mutation.key());
+            self.summary.timed_out.push(mutation.to_string());
+        }
+        Ok(())
+    }
+
+    /// Throw away all uncommitted changes to the tree.
+    fn revert(&self) -> Result<()> {
+        self.git(&["checkout", "."])
+    }
+
+    /// Format the tree and commit every change to it, which is `batches` applied.
+    ///
+    /// If only some packages were checked since the last commit, the whole workspace is
+    /// checked first. Should that fail, the changes are reverted instead, nothing is
+    /// committed and the session checks the whole workspace from then on. Returns
+    /// whether the changes were committed.
+    fn commit_changes(&mut self, batches: &[Batch]) -> Result<bool> {
+        if self.unchecked_workspace {
+            println!("WORKSPACE before committing");
+            let log = self.next_attempt();
+            let verdict = verify(
+                &self.check,
+                &Scope::Workspace,
+                self.tree.root(),
+                &log,
+                self.timeouts.cargo(),
+            )?;
+            self.record_attempt(&verdict, log);
+            self.unchecked_workspace = false;
+            if !verdict.pass() {
+                println!("{}, checking the whole workspace from now on", verdict);
+                self.check.affected_only = false;
+                self.revert()?;
+                return Ok(false);
+            }
+        }
+        let mutations: Vec<&Mutation> = batches.iter().flat_map(Batch::iter).collect();
+        if run_cargo(&["fmt"], self.tree.root(), self.timeouts.cargo())? == CargoResult::Timeout {
+            return Err(anyhow!("cargo fmt timed out"));
+        }
+        let args = self.commit_args(&mutations);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.git(&args)?;
+        Ok(true)
+    }
+
+    /// Return the arguments to `git commit` every change to the tree, which is
+    /// `mutations` applied.
+    ///
+    /// The commit is limited to the tree, so that changes elsewhere in the repo, which
+    /// are not stashed, stay uncommitted.
+    fn commit_args(&self, mutations: &[&Mutation]) -> Vec<String> {
+        let mut args: Vec<String> = vec!["commit".into(), "--message".into()];
+        args.push(message(mutations, self.seed, &self.commit.trailers));
+        args.extend(
+            self.commit
+                .author
+                .as_ref()
+                .map(|a| format!("--author={}", a)),
+        );
+        args.extend(["--".into(), ".".into()]);
+        args
+    }
+
+    /// Check out the branch to commit to, creating it from the checked out commit if it
+    /// doesn't exist yet. Returns what was checked out before, to go back to, unless
+    /// that was the branch already.
+    ///
+    /// An existing branch is only used if it has every checked out commit, so that
+    /// none are left out. Otherwise a branch named after it with a suffix such as `-2`
+    /// is used instead, under the same condition.
+    fn switch_branch(&mut self) -> Result<Option<String>> {
+        if self.commit.branch.is_empty() {
+            return Ok(None);
+        }
+        let branch = branch_name(&self.commit.branch, SystemTime::now());
+        let root = self.tree.root();
+        let timeout = self.timeouts.git();
+        let original = match git_output(
+            &["symbolic-ref", "--quiet", "--short", "HEAD"],
+            root,
+            timeout,
+        ) {
+            Ok(name) => name,
+            // Detached.
+            Err(_) => git_output(&["rev-parse", "HEAD"], root, timeout)?,
+        };
+        let original = original.trim().to_owned();
+        let mut name = branch.clone();
+        for suffix in 2.. {
+            if original == name {
+                return Ok(None);
+            }
+            let exists = git_output(
+                &[
+                    "rev-parse",
+                    "--verify",
+                    "--quiet",
+                    &format!("refs/heads/{}", name),
+                ],
+                root,
+                timeout,
+            )
+            .is_ok();
+            if !exists {
+                self.git(&["checkout", "-b", &name])?;
+                println!("BRANCH created {}", name);
+                break;
+            }
+            match run_git(
+                &["merge-base", "--is-ancestor", "HEAD", &name],
+                root,
+                timeout,
+            )? {
+                GitResult::Success => {
+                    self.git(&["checkout", &name])?;
+                    println!("BRANCH switched to {}", name);
+                    // It may have commits from an earlier run.
+                    self.tree.refresh();
+                    break;
+                }
+                GitResult::Failure => {}
+                GitResult::Timeout => return Err(anyhow!("git merge-base timed out")),
+            }
+            println!("BRANCH {} lacks commits that are checked out", name);
+            name = format!("{}-{}", branch, suffix);
+        }
+        Ok(Some(original))
+    }
+
+    /// Undo whatever is left uncommitted and check out `original` again.
+    ///
+    /// This works after an interrupt too, which stops [Session::git].
+    fn switch_back(&mut self, original: &str) -> Result<()> {
+        let root = self.tree.root();
+        let timeout = self.timeouts.git();
+        git_output(&["checkout", "--", "."], root, timeout)?;
+        git_output(&["checkout", original], root, timeout)?;
+        println!("BRANCH back on {}", original);
+        self.tree.refresh();
+        Ok(())
+    }
+
+    /// Run git in the tree, failing if it fails or times out.
+    fn git(&self, args: &[&str]) -> Result<()> {
+        match run_git(args, self.tree.root(), self.timeouts.git())? {
+            GitResult::Success => Ok(()),
+            GitResult::Failure => Err(anyhow!("git {} failed", args.join(" "))),
+            GitResult::Timeout => Err(anyhow!("git {} timed out", args.join(" "))),
+        }
+    }
+}
+
+impl Bisect<Mutation> for Session {
+    fn try_changes(&mut self, trial: &[Mutation]) -> Result<Verdict> {
+        let verdict = self.check_batch(&trial.iter().cloned().collect())?;
+        self.revert()?;
+        Ok(verdict)
+    }
+
+    fn reject_change(&mut self, change: &Mutation, stage: &str) -> Result<()> {
+        self.reject(change, stage)
+    }
+
+    fn time_out_changes(&mut self, changes: &[Mutation]) -> Result<()> {
+        self.time_out(&changes.iter().cloned().collect())
+    }
+}
+
+/// Return the file and item `mutation` changes the visibility of.
+fn item_of(mutation: &Mutation) -> (PathBuf, String) {
+    (
+        mutation.source_file.tree_relative_path().to_owned(),
+        mutation.item().to_owned(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    use super::*;
+    use crate::config::Stage;
+    use crate::testing::{crate_in, manifest, repo_in};
+
+    /// A check with one stage that runs `script` in the shell.
+    fn shell_check(script: &str) -> CheckConfig {
+        CheckConfig {
+            command: "sh".to_owned(),
+            stages: vec![Stage {
+                name: "script".to_owned(),
+                args: vec!["-c".to_owned(), script.to_owned()],
+                env: BTreeMap::new(),
+            }],
+            ..CheckConfig::default()
+        }
+    }
+
+    /// Check batches with `jobs` fake workers that give each the verdict of `verdict`,
+    /// until the session stops handing them out.
+    fn coordinate(
+        session: &mut Session,
+        jobs: usize,
+        verdict: impl Fn(&Batch) -> Verdict + Clone + Send + 'static,
+    ) {
+        let (done_tx, done_rx) = channel();
+        let workers: Vec<Worker> = (0..jobs)
+            .map(|index| {
+                let verdict = verdict.clone();
+                Worker::fake(index, done_tx.clone(), move |job| {
+                    // Logged to, as a real worker would.
+                    std::fs::create_dir_all(job.log.parent().unwrap())?;
+                    std::fs::write(&job.log, "")?;
+                    Ok(verdict(&job.batch))
+                })
+            })
+            .collect();
+        drop(done_tx);
+        session.coordinate(&workers, &done_rx).unwrap();
+    }
+
+    fn read(root: &Path, path: &str) -> String {
+        std::fs::read_to_string(root.join(path)).unwrap()
+    }
+
+    fn commits(root: &Path) -> usize {
+        git_output(&["rev-list", "--count", "HEAD"], root, None)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn same_seed_samples_same_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib: String = (0..10).map(|i| format!("pub struct S{};\n", i)).collect();
+        crate_in(dir.path(), &[("src/lib.rs", &lib)]);
+        let batches = |seed: u64| -> Vec<Vec<String>> {
+            let tree = SourceTree::new(dir.path(), None).unwrap();
+            let mut session = Session::new(tree, seed);
+            (0..3)
+                .map(|_| {
+                    session
+                        .sample()
+                        .unwrap()
+                        .iter()
+                        .map(|m| m.to_string())
+                        .collect()
+                })
+                .collect()
+        };
+        assert_eq!(batches(7), batches(7));
+        assert_ne!(batches(7), batches(8));
+    }
+
+    #[test]
+    fn exhaustive_tries_each_undecided_mutation_once() {
+        let dir = tempfile::tempdir().unwrap();
+        crate_in(
+            dir.path(),
+            &[("src/lib.rs", "pub struct A;\npub(crate) struct B;\n")],
+        );
+        let tree = SourceTree::new(dir.path(), None).unwrap();
+        let mutations: Vec<Mutation> = tree.mutations().cloned().collect();
+        let mut state = State::empty(dir.path());
+        state.record(mutations[1].key(), Outcome::Rejected).unwrap();
+        let mut session = Session::new(tree, 1)
+            .state(state)
+            .check(shell_check("false"))
+            .commit(CommitConfig {
+                branch: String::new(),
+                ..CommitConfig::default()
+            });
+        session.run(Strategy::Exhaustive).unwrap();
+
+        let tried: Vec<&str> = session
+            .summary()
+            .rejected
+            .iter()
+            .map(|r| r.mutation.as_str())
+            .collect();
+        let expected: Vec<String> = mutations
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 1)
+            .map(|(_, m)| m.to_string())
+            .collect();
+        assert_eq!(tried, expected);
+        assert_eq!(session.summary().batches, mutations.len() - 1);
+    }
+
+    #[test]
+    fn random_stops_when_every_mutation_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        crate_in(dir.path(), &[("src/lib.rs", "pub struct A;\n")]);
+        let tree = SourceTree::new(dir.path(), None).unwrap();
+        let mut state = State::empty(dir.path());
+        for mutation in tree.mutations() {
+            state.record(mutation.key(), Outcome::Rejected).unwrap();
+        }
+        let mut session = Session::new(tree, 1)
+            .state(state)
+            .check(shell_check("true"))
+            .commit(CommitConfig {
+                branch: String::new(),
+                ..CommitConfig::default()
+            });
+        assert!(session.sample().unwrap().is_empty());
+        session.run(Strategy::Random).unwrap();
+        assert_eq!(session.summary().batches, 0);
+    }
+
+    #[test]
+    fn every_attempt_is_logged_to_its_own_file() {
+        let dir = tempfile::tempdir().unwrap();
+        crate_in(dir.path(), &[("src/lib.rs", "pub struct A;\n")]);
+        let tree = SourceTree::new(dir.path(), None).unwrap();
+        let mut session = Session::new(tree, 1)
+            .state(State::empty(dir.path()))
+            .check(shell_check(
+                "cat src/lib.rs; ! grep -q 'pub(self)' src/lib.rs",
+            ))
+            .commit(CommitConfig {
+                branch: String::new(),
+                ..CommitConfig::default()
+            });
+        session.run(Strategy::Exhaustive).unwrap();
+
+        let summary = session.summary();
+        assert_eq!(summary.attempts.len(), summary.batches);
+        let logs: HashSet<&PathBuf> = summary.attempts.iter().map(|a| &a.log).collect();
+        assert_eq!(logs.len(), summary.attempts.len());
+        for attempt in &summary.attempts {
+            let log = std::fs::read_to_string(&attempt.log).unwrap();
+            assert!(log.starts_with("==> STAGE script: sh -c"), "{}", log);
+        }
+        assert!(!summary.rejected.is_empty());
+        let text = summary.to_string();
+        for rejected in &summary.rejected {
+            let attempt = summary
+                .attempts
+                .iter()
+                .find(|a| a.log == rejected.log)
+                .unwrap();
+            assert_eq!(attempt.verdict, Verdict::Fail("script".to_owned()));
+            let log = std::fs::read_to_string(&rejected.log).unwrap();
+            assert!(log.contains("pub(self) struct A;"), "{}", log);
+            assert!(text.contains(&format!("see {}", rejected.log.display())));
+        }
+    }
+
+    #[test]
+    fn greedy_commits_every_few_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        crate_in(
+            dir.path(),
+            &[(
+                "src/lib.rs",
+                "pub struct A;\npub struct B;\npub struct C;\n",
+            )],
+        );
+        let tree = SourceTree::new(dir.path(), None).unwrap();
+        let mut session = Session::new(tree, 1)
+            .state(State::empty(dir.path()))
+            .check(shell_check("! grep -q 'pub(self)' src/lib.rs"))
+            .commit(CommitConfig {
+                branch: String::new(),
+                ..CommitConfig::default()
+            })
+            .commit_every(2);
+        session.run(Strategy::Greedy).unwrap();
+
+        let accepted = session.summary().accepted.len();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("src/lib.rs")).unwrap(),
+            "struct A;\nstruct B;\nstruct C;\n"
+        );
+        assert_eq!(commits(dir.path()), 1 + accepted.div_ceil(2));
+        let state = State::load(dir.path()).unwrap();
+        let rejected = session.summary().rejected.len();
+        assert_eq!(state.len(), accepted + rejected);
+        assert!(rejected > 0);
+    }
+
+    #[test]
+    fn greedy_tries_refused_commit_again() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = format!("{}\n[workspace]\nmembers = [\"b\"]\n", manifest("demo"));
+        crate_in(
+            dir.path(),
+            &[
+                ("Cargo.toml", &workspace),
+                ("src/lib.rs", "pub struct A;\n"),
+                ("b/Cargo.toml", &manifest("b")),
+                ("b/src/lib.rs", "pub struct B;\n"),
+            ],
+        );
+        // Stands in for cargo, noting its arguments and failing only when the whole
+        // workspace is checked with `B` made private.
+        let bin = tempfile::tempdir().unwrap();
+        let cargo = bin.path().join("cargo");
+        let calls = bin.path().join("calls");
+        std::fs::write(
+            &cargo,
+            format!(
+                "#!/bin/sh\necho \"$*\" >> {:?}\n\
+                 case \" $* \" in *' --workspace '*) grep -q 'pub struct B' b/src/lib.rs;; esac\n",
+                calls
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&cargo, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let tree = SourceTree::new(dir.path(), None).unwrap();
+        let mut session = Session::new(tree, 1)
+            .state(State::empty(dir.path()))
+            .check(CheckConfig {
+                command: cargo.to_str().unwrap().to_owned(),
+                stages: vec![Stage {
+                    name: "check".to_owned(),
+                    args: vec!["check".to_owned(), "--workspace".to_owned()],
+                    env: BTreeMap::new(),
+                }],
+                ..CheckConfig::default()
+            })
+            .commit(CommitConfig {
+                branch: String::new(),
+                ..CommitConfig::default()
+            })
+            .commit_every(100);
+        session.run(Strategy::Greedy).unwrap();
+
+        assert_eq!(read(dir.path(), "src/lib.rs"), "struct A;\n");
+        assert_eq!(read(dir.path(), "b/src/lib.rs"), "pub struct B;\n");
+        assert_eq!(commits(dir.path()), 2);
+        // Passed when only `b` was checked, then refused by the check of the workspace.
+        let calls = std::fs::read_to_string(calls).unwrap();
+        assert!(calls.lines().any(|line| line == "check -p b"), "{}", calls);
+        let summary = session.summary();
+        assert!(summary.accepted.iter().all(|m| m.starts_with("src/lib.rs")));
+        assert!(!summary.rejected.is_empty());
+        assert!(summary
+            .rejected
+            .iter()
+            .all(|r| r.mutation.starts_with("b/src/lib.rs")));
+    }
+
+    #[test]
+    fn greedy_commits_accepted_when_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        crate_in(root, &[("src/lib.rs", "pub struct A;\npub struct B;\n")]);
+        let tree = SourceTree::new(root, None).unwrap();
+        // The third check passes, but leaves the state impossible to save, which
+        // stops the run like an interrupt would.
+        let mut session = Session::new(tree, 1)
+            .state(State::empty(root))
+            .check(shell_check(
+                "n=$(($(cat target/checks 2>/dev/null || echo 0) + 1)); echo $n > target/checks; \
+                 [ $n -lt 3 ] || { rm target/retypist/state.json; \
+                 mkdir -p target/retypist/state.json/full; }",
+            ))
+            .commit(CommitConfig {
+                branch: String::new(),
+                ..CommitConfig::default()
+            })
+            .commit_every(100);
+        assert!(session.run(Strategy::Greedy).is_err());
+
+        assert_eq!(commits(root), 2);
+        assert_eq!(session.summary().accepted.len(), 2);
+        assert_eq!(
+            git_output(&["status", "--porcelain"], root, None).unwrap(),
+            ""
+        );
+        assert_ne!(read(root, "src/lib.rs"), "pub struct A;\npub struct B;\n");
+    }
+
+    #[test]
+    fn switch_to_new_branch_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        crate_in(dir.path(), &[("src/lib.rs", "pub struct A;\n")]);
+        let head = |root: &Path| {
+            git_output(&["symbolic-ref", "--short", "HEAD"], root, None)
+                .unwrap()
+                .trim()
+                .to_owned()
+        };
+        let original = head(dir.path());
+        let tree = SourceTree::new(dir.path(), None).unwrap();
+        let mut session = Session::new(tree, 1).commit(CommitConfig {
+            branch: "retypist/test".to_owned(),
+            ..CommitConfig::default()
+        });
+
+        assert_eq!(session.switch_branch().unwrap(), Some(original.clone()));
+        assert_eq!(head(dir.path()), "retypist/test");
+        // Already on it.
+        assert_eq!(session.switch_branch().unwrap(), None);
+
+        std::fs::write(dir.path().join("src/lib.rs"), "struct A;\n").unwrap();
+        session.switch_back(&original).unwrap();
+        assert_eq!(head(dir.path()), original);
+        assert_eq!(read(dir.path(), "src/lib.rs"), "pub struct A;\n");
+    }
+
+    #[test]
+    fn commit_only_changes_to_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        repo_in(
+            dir.path(),
+            &[
+                ("NOTES.md", "notes\n"),
+                (".gitignore", "target\nCargo.lock\n"),
+                ("crates/foo/Cargo.toml", &manifest("foo")),
+                ("crates/foo/src/lib.rs", "pub struct A;\n"),
+            ],
+        );
+        // Uncommitted work outside the tree, which is not stashed.
+        std::fs::write(dir.path().join("NOTES.md"), "more notes\n").unwrap();
+        let root = dir.path().join("crates/foo");
+        let tree = SourceTree::new(&root, None).unwrap();
+        let mut session = Session::new(tree, 1)
+            .state(State::empty(&root))
+            .check(shell_check("true"))
+            .commit(CommitConfig {
+                branch: "retypist/test".to_owned(),
+                ..CommitConfig::default()
+            });
+        session.run(Strategy::Greedy).unwrap();
+
+        assert_eq!(read(dir.path(), "NOTES.md"), "more notes\n");
+        assert_eq!(
+            git_output(
+                &["diff", "--name-only", "HEAD", "retypist/test"],
+                dir.path(),
+                None
+            )
+            .unwrap(),
+            "crates/foo/src/lib.rs\n"
+        );
+    }
+
+    #[test]
+    fn reuse_branch_only_if_it_has_every_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        crate_in(root, &[("src/lib.rs", "pub struct A;\n")]);
+        let commit = |message: &str| {
+            git_output(
+                &["commit", "--quiet", "--all", "--message", message],
+                root,
+                None,
+            )
+            .unwrap();
+        };
+        let tree = SourceTree::new(root, None).unwrap();
+        let mut session = Session::new(tree, 1).commit(CommitConfig {
+            branch: "retypist/test".to_owned(),
+            ..CommitConfig::default()
+        });
+
+        // Ahead, from an earlier run.
+        let original = session.switch_branch().unwrap().unwrap();
+        std::fs::write(root.join("src/lib.rs"), "pub(crate) struct A;\n").unwrap();
+        commit("Earlier run");
+        session.switch_back(&original).unwrap();
+        assert_eq!(session.switch_branch().unwrap(), Some(original.clone()));
+        assert_eq!(read(root, "src/lib.rs"), "pub(crate) struct A;\n");
+        session.switch_back(&original).unwrap();
+
+        // Behind the user's newer work.
+        std::fs::write(root.join("src/lib.rs"), "pub struct A;\npub struct B;\n").unwrap();
+        commit("Newer work");
+        assert_eq!(session.switch_branch().unwrap(), Some(original.clone()));
+        let head = git_output(&["symbolic-ref", "--short", "HEAD"], root, None).unwrap();
+        assert_eq!(head.trim(), "retypist/test-2");
+        assert_eq!(read(root, "src/lib.rs"), "pub struct A;\npub struct B;\n");
+        let earlier =
+            git_output(&["log", "-1", "--format=%s", "retypist/test"], root, None).unwrap();
+        assert_eq!(earlier.trim(), "Earlier run");
+    }
+
+    #[test]
+    fn parallel_batches_are_rechecked_without_counting_as_idle() {
+        // More items than fit in one batch, so that every worker gets one.
+        let items = |vis: &str| -> String {
+            (0..6)
+                .map(|i| format!("{} struct S{};\n", vis, i).trim_start().to_owned())
+                .collect()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        crate_in(
+            dir.path(),
+            &[
+                ("src/lib.rs", "mod a;\nmod b;\nmod c;\n"),
+                ("src/a.rs", &items("pub")),
+                ("src/b.rs", &items("pub")),
+                ("src/c.rs", &items("pub")),
+            ],
+        );
+        let tree = SourceTree::new(dir.path(), None).unwrap();
+        let mut session = Session::new(tree, 2)
+            .state(State::empty(dir.path()))
+            .commit(CommitConfig {
+                branch: String::new(),
+                ..CommitConfig::default()
+            })
+            .max_idle_batches(1);
+        // Every batch but the first to finish passed on top of an older commit.
+        coordinate(&mut session, 3, |_| Verdict::Pass);
+
+        for path in ["src/a.rs", "src/b.rs", "src/c.rs"] {
+            assert_eq!(read(dir.path(), path), items(""));
+        }
+        assert!(session.summary().batches > commits(dir.path()) - 1);
+        assert!(session.summary().rejected.is_empty());
+        // Every item has several mutations, which can't share a batch.
+        assert!(session.summary().overlaps > 0);
+    }
+
+    #[test]
+    fn parallel_failures_are_attributed() {
+        let dir = tempfile::tempdir().unwrap();
+        crate_in(
+            dir.path(),
+            &[(
+                "src/lib.rs",
+                "pub struct A;\npub struct B;\npub struct C;\npub struct D;\n",
+            )],
+        );
+        let tree = SourceTree::new(dir.path(), None).unwrap();
+        let culprit = tree
+            .mutations()
+            .find(|m| m.item() == "B" && m.op == crate::mutation::MutationOp::ToVisSelf)
+            .unwrap()
+            .to_string();
+        // Points at the line of `B` when it is made private, as cargo would.
+        let error = r#"{"reason":"compiler-message","message":{"message":"struct B is private","level":"error","spans":[{"file_name":"src/lib.rs","line_start":2,"line_end":2,"is_primary":true}],"children":[]}}"#;
+        let mut session = Session::new(tree, 2)
+            .state(State::empty(dir.path()))
+            .check(shell_check(&format!(
+                "! grep -q 'pub(self) struct B' src/lib.rs || {{ echo '{}'; exit 1; }}",
+                error
+            )))
+            .commit(CommitConfig {
+                branch: String::new(),
+                ..CommitConfig::default()
+            })
+            .max_idle_batches(1);
+        let failed = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let failing = failed.clone();
+        coordinate(&mut session, 1, move |batch| {
+            let culprit = batch
+                .iter()
+                .any(|m| m.item() == "B" && m.op == crate::mutation::MutationOp::ToVisSelf);
+            if culprit && batch.len() > 1 {
+                let others = batch
+                    .iter()
+                    .filter(|m| m.item() != "B")
+                    .map(|m| m.to_string())
+                    .collect::<Vec<_>>();
+                failing.lock().unwrap().push(others);
+            }
+            if culprit {
+                Verdict::Fail("script".to_owned())
+            } else {
+                Verdict::Pass
+            }
+        });
+
+        let failed = failed.lock().unwrap();
+        assert!(!failed.is_empty());
+        for mutation in failed.iter().flatten() {
+            assert!(
+                session.summary().accepted.contains(mutation),
+                "{}",
+                mutation
+            );
+        }
+        // Checked on its own after the first failure, so it isn't tried again.
+        let rejected: Vec<&str> = session
+            .summary()
+            .rejected
+            .iter()
+            .map(|r| r.mutation.as_str())
+            .collect();
+        assert_eq!(rejected, vec![culprit.as_str()]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            read(dir.path(), "src/lib.rs"),
+            "struct A;\nstruct B;\nstruct C;\nstruct D;\n"
+        );
+    }
+}
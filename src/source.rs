@@ -1,14 +1,22 @@
 // Bits taken from Martin Pool's cargo-mutants, copyright 2021 under the MIT
 // license.
 
-use crate::{mutation::Mutation, visitor::Visitor};
-use anyhow::{anyhow, Context, Result};
-use rand::prelude::SliceRandom;
-use rand::seq::IteratorRandom;
+use crate::{
+    batch::Batch,
+    index::Index,
+    metadata::Metadata,
+    modules::{module_tree, read_declarations},
+    mutation::Mutation,
+    pipeline::Scope,
+    visitor::Visitor,
+};
+use anyhow::{Context, Result};
 use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 use syn::visit::Visit;
 
 /// A Rust source file within a source tree.
@@ -23,114 +31,337 @@ pub struct SourceFile {
     /// Path of the file, including the user passed working directory
     path: PathBuf,
 
+    /// Path of the file relative to the root of the source tree.
+    tree_relative: PathBuf,
+
+    /// Name of the package the file belongs to.
+    package: String,
+
+    /// Name of the package's target the file belongs to.
+    target: String,
+
+    /// Path of the module the file holds, such as `crate::net::tcp`.
+    module_path: String,
+
     /// Full copy of the source.
-    pub code: Rc<String>,
+    pub code: Arc<String>,
 }
 
 impl fmt::Debug for SourceFile {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("SourceFile")
             .field("path", &self.path)
+            .field("tree_relative", &self.tree_relative)
+            .field("package", &self.package)
+            .field("target", &self.target)
+            .field("module_path", &self.module_path)
             .finish()
     }
 }
 
 impl SourceFile {
-    /// Construct a SourceFile representing a file within a tree.
+    /// Construct a SourceFile representing the file of module `module_path` of `target`
+    /// of `package`, within a tree.
     ///
     /// This eagerly loads the text of the file.
-    pub fn new(tree_path: &Path, tree_relative: &Path) -> Result<SourceFile> {
+    pub fn new(
+        tree_path: &Path,
+        tree_relative: &Path,
+        package: &str,
+        target: &str,
+        module_path: &str,
+    ) -> Result<SourceFile> {
         let full_path = tree_path.join(tree_relative);
         let code = std::fs::read_to_string(&full_path)
             .with_context(|| format!("failed to read source of {:?}", full_path))?
             .replace("\r\n", "\n");
         Ok(SourceFile {
             path: full_path,
-            code: Rc::new(code),
+            tree_relative: tree_relative.to_owned(),
+            package: package.to_owned(),
+            target: target.to_owned(),
+            module_path: module_path.to_owned(),
+            code: Arc::new(code),
         })
     }
 
+    /// Construct a SourceFile from code that is already in memory.
+    #[cfg(test)]
+    pub(crate) fn from_code(path: &Path, code: &str) -> SourceFile {
+        SourceFile {
+            path: path.to_owned(),
+            tree_relative: path.to_owned(),
+            package: String::new(),
+            target: String::new(),
+            module_path: "crate".to_owned(),
+            code: Arc::new(code.replace("\r\n", "\n")),
+        }
+    }
+
+    /// Path of the file, including the user passed working directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of the file relative to the root of the source tree, for display.
+    pub fn tree_relative_path(&self) -> &Path {
+        &self.tree_relative
+    }
+
+    /// Name of the package the file belongs to.
+    pub fn package(&self) -> &str {
+        &self.package
+    }
+
+    /// Name of the target the file belongs to, such as the library or one of the
+    /// package's tests.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Path of the module the file holds, such as `crate::net::tcp`.
+    pub fn module_path(&self) -> &str {
+        &self.module_path
+    }
+
     /// Generate a list of all mutation possibilities within this file.
     pub fn mutations(&self) -> Result<Vec<Mutation>> {
         let syn_file = syn::parse_str::<syn::File>(&self.code)?;
+        self.mutations_in(&syn_file)
+    }
+
+    /// Generate a list of all mutation possibilities within this file, given its
+    /// already parsed code.
+    pub fn mutations_in(&self, syn_file: &syn::File) -> Result<Vec<Mutation>> {
         let mut v = Visitor::new(self);
-        v.visit_file(&syn_file);
-        Ok(v.mutations)
+        v.visit_file(syn_file);
+        match v.errors.pop() {
+            Some(err) => Err(err),
+            None => Ok(v.mutations),
+        }
     }
 
-    pub fn rewrite(&mut self, edit: String) -> Result<()> {
+    pub fn rewrite(&self, edit: String) -> Result<()> {
         std::fs::write(&self.path, &edit).map_err(|e| e.into())
     }
 }
 
+/// The root source file of one target of a workspace member.
+#[derive(Debug)]
+struct TargetRoot {
+    package: String,
+    target: String,
+
+    /// Absolute path of the target's root file, such as `src/lib.rs`.
+    src_path: PathBuf,
+}
+
 #[derive(Debug)]
 pub struct SourceTree {
     root: PathBuf,
+
+    /// `root` made absolute, to match the paths reported by cargo.
+    absolute_root: PathBuf,
+
+    /// Every target of every workspace member.
+    targets: Vec<TargetRoot>,
+
+    /// For every workspace member, the members that depend on it directly.
+    dependents: BTreeMap<String, BTreeSet<String>>,
+
+    /// The tree's source files as last read, with their possible mutations.
+    index: Index,
 }
 
 impl SourceTree {
-    pub fn new(root: &Path) -> Result<SourceTree> {
-        if !root.join("Cargo.toml").is_file() {
-            return Err(anyhow!(
-                "{} does not contain a Cargo.toml: specify a crate directory",
-                root.to_str().unwrap(),
-            ));
+    /// Open the tree at `root`, finding its packages and targets with `cargo metadata`,
+    /// which may run for up to `timeout`.
+    pub fn new(root: &Path, timeout: Option<Duration>) -> Result<SourceTree> {
+        let absolute_root = root
+            .canonicalize()
+            .with_context(|| format!("failed to open source tree {:?}", root))?;
+        let metadata = Metadata::load(root, timeout)?;
+        let mut targets = Vec::new();
+        for package in metadata.members() {
+            for target in package.targets.iter().filter(|t| !t.is_build_script()) {
+                targets.push(TargetRoot {
+                    package: package.name.clone(),
+                    target: target.name.clone(),
+                    src_path: target.src_path.clone(),
+                });
+            }
         }
-        Ok(SourceTree {
+        let mut tree = SourceTree {
             root: root.to_owned(),
-        })
+            absolute_root,
+            targets,
+            dependents: metadata.dependents(),
+            index: Index::default(),
+        };
+        tree.refresh();
+        Ok(tree)
+    }
+
+    /// Read the tree's source files again and parse those that changed since they were
+    /// last read. Returns the number of files parsed.
+    ///
+    /// A target's files are those of its module tree, found by following `mod`
+    /// declarations from its root file. The declarations are kept in the index along
+    /// with the mutations, so unchanged files are not parsed again to find them. A file
+    /// in several targets' module trees belongs to the first of them.
+    pub fn refresh(&mut self) -> usize {
+        let mut parsed = 0;
+        let mut paths = BTreeSet::new();
+        for target in &self.targets {
+            module_tree(&target.src_path, |file| {
+                let tree_relative = match file.path.strip_prefix(&self.absolute_root) {
+                    Ok(tree_relative) => tree_relative,
+                    // Members outside the root can't be checked out or committed from it,
+                    // so their files are only followed, not indexed.
+                    Err(_) => return read_declarations(&file.path),
+                };
+                if paths.insert(tree_relative.to_owned()) {
+                    let sf = SourceFile::new(
+                        &self.root,
+                        tree_relative,
+                        &target.package,
+                        &target.target,
+                        &file.module_path,
+                    )?;
+                    parsed += usize::from(self.index.update(sf));
+                }
+                Ok(self.index.modules(tree_relative).to_vec())
+            });
+        }
+        self.index.retain(&paths);
+        parsed
     }
 
     pub fn root(&self) -> &Path {
         self.root.as_path()
     }
 
-    /// Return an iterator of `src/**/*.rs` paths relative to the root.
-    pub fn source_files(&self) -> impl Iterator<Item = SourceFile> + '_ {
-        walkdir::WalkDir::new(self.root.join("src"))
-            .sort_by_file_name()
+    /// Return the packages a check of `mutations` has to cover: those they mutate and
+    /// every member that depends on them, directly or not.
+    pub fn scope<'a>(&self, mutations: impl IntoIterator<Item = &'a Mutation>) -> Scope {
+        let mut affected = BTreeSet::new();
+        let mut queue: Vec<&str> = mutations
             .into_iter()
-            .filter_map(|r| {
-                r.map_err(|err| eprintln!("error walking source tree: {:?}", err))
-                    .ok()
-            })
-            .filter(|entry| entry.file_type().is_file())
-            .map(|entry| entry.into_path())
-            .filter(|path| {
-                path.extension()
-                    .map_or(false, |p| p.eq_ignore_ascii_case("rs"))
-            })
-            .filter_map(move |full_path| {
-                let tree_relative = full_path.strip_prefix(&self.root).unwrap();
-                SourceFile::new(&self.root, tree_relative)
-                    .map_err(|err| {
-                        eprintln!(
-                            "error reading source {}: {}",
-                            full_path.to_str().unwrap(),
-                            err
-                        );
-                    })
-                    .ok()
-            })
-    }
-
-    /// Return possible mutations for the tree.
-    pub fn mutation(&self) -> Result<Vec<Mutation>> {
-        let mut rng = rand::thread_rng();
-        let total: usize = rng.gen_range(1..16);
-        let mut mutations = Vec::with_capacity(total);
-        // TODO add a timeout for search here or something, could loop forever
-        while mutations.len() < total {
-            let sf = self.source_files().choose(&mut rng);
-            if let Some(sf) = sf {
-                let mut possible_mutants = sf.mutations()?;
-                if possible_mutants.is_empty() {
-                    continue;
+            .map(|m| m.source_file.package())
+            .collect();
+        while let Some(package) = queue.pop() {
+            if affected.insert(package.to_owned()) {
+                if let Some(dependents) = self.dependents.get(package) {
+                    queue.extend(dependents.iter().map(String::as_str));
                 }
-                possible_mutants.shuffle(&mut rng);
-                mutations.push(possible_mutants.pop().unwrap());
+            }
+        }
+        if affected.len() >= self.dependents.len() {
+            Scope::Workspace
+        } else {
+            Scope::Packages(affected)
+        }
+    }
+
+    /// Return every possible mutation in the tree as last read, in a stable order.
+    pub fn mutations(&self) -> impl Iterator<Item = &Mutation> {
+        self.index.mutations()
+    }
+
+    /// Return a random batch of up to 15 possible mutations for the tree, leaving out those
+    /// for which `skip` returns true.
+    ///
+    /// A file is picked at random and then a mutation within it, so small files are not
+    /// drowned out by large ones. The batch is empty only when no candidates are left.
+    ///
+    /// All randomness comes from `rng`, so a seeded generator gives the same batch for the
+    /// same tree.
+    pub fn mutation<R: Rng>(&self, rng: &mut R, skip: impl Fn(&Mutation) -> bool) -> Result<Batch> {
+        let total: usize = rng.gen_range(1..16);
+        let mut mutations = Batch::with_capacity(total);
+        let mut by_file: Vec<Vec<&Mutation>> = self
+            .index
+            .mutations_by_file()
+            .map(|possible_mutants| possible_mutants.iter().filter(|m| !skip(m)).collect())
+            .filter(|possible_mutants: &Vec<&Mutation>| !possible_mutants.is_empty())
+            .collect();
+        // Every pass takes one candidate out of `by_file`, so this ends even if most of
+        // them conflict.
+        while mutations.len() < total && !by_file.is_empty() {
+            let file_idx = rng.gen_range(0..by_file.len());
+            let possible_mutants = &mut by_file[file_idx];
+            let mutant_idx = rng.gen_range(0..possible_mutants.len());
+            let _ = mutations.push(possible_mutants.swap_remove(mutant_idx).clone());
+            if possible_mutants.is_empty() {
+                by_file.swap_remove(file_idx);
             }
         }
         Ok(mutations)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::testing::{manifest, write_files};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn refresh_parses_only_changed_modules() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_files(
+            root,
+            &[
+                ("Cargo.toml", &manifest("demo")),
+                ("src/lib.rs", "pub mod a;\n"),
+                ("src/a.rs", "pub struct A;\n"),
+                ("src/b.rs", "pub struct B;\n"),
+            ],
+        );
+        let files = |tree: &SourceTree| -> Vec<String> {
+            let mut files: Vec<String> = tree
+                .mutations()
+                .map(|m| m.source_file.module_path().to_owned())
+                .collect();
+            files.dedup();
+            files
+        };
+
+        let mut tree = SourceTree::new(root, None).unwrap();
+        assert_eq!(files(&tree), vec!["crate::a"]);
+        assert_eq!(tree.refresh(), 0);
+
+        std::fs::write(root.join("src/lib.rs"), "pub mod a;\npub mod b;\n").unwrap();
+        assert_eq!(tree.refresh(), 2);
+        assert_eq!(files(&tree), vec!["crate::a", "crate::b"]);
+        assert_eq!(tree.refresh(), 0);
+
+        std::fs::write(root.join("src/lib.rs"), "pub mod b;\n").unwrap();
+        assert_eq!(tree.refresh(), 1);
+        assert_eq!(files(&tree), vec!["crate::b"]);
+    }
+
+    #[test]
+    fn empty_batch_when_no_candidates_are_left() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_files(
+            root,
+            &[
+                ("Cargo.toml", &manifest("demo")),
+                ("src/lib.rs", "pub struct A;\n"),
+            ],
+        );
+        let mut rng = StdRng::seed_from_u64(1);
+        let tree = SourceTree::new(root, None).unwrap();
+        assert!(!tree.mutation(&mut rng, |_| false).unwrap().is_empty());
+        assert!(tree.mutation(&mut rng, |_| true).unwrap().is_empty());
+
+        std::fs::write(root.join("src/lib.rs"), "struct A;\n").unwrap();
+        let tree = SourceTree::new(root, None).unwrap();
+        assert!(tree.mutation(&mut rng, |_| false).unwrap().is_empty());
+    }
+}
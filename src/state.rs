@@ -0,0 +1,190 @@
+//! Outcomes of mutations, remembered across runs so that mutations already known to break
+//! the build are not tried again until the code around them changes.
+
+use crate::mutation::MutationKey;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Where the state is stored, relative to the tree root.
+const STATE_PATH: &str = "target/retypist/state.json";
+
+/// What happened when a mutation was checked.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Outcome {
+    /// The mutation passed the check and was committed.
+    Accepted,
+    /// The mutation broke the check.
+    Rejected,
+    /// The check of a batch holding the mutation ran out of time, so its outcome is
+    /// unknown.
+    TimedOut,
+}
+
+/// One remembered outcome, as stored in the state file.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    key: MutationKey,
+    outcome: Outcome,
+}
+
+/// The outcome of every mutation decided in this or an earlier run.
+#[derive(Debug, Default)]
+pub struct State {
+    /// File the state is saved to; `None` keeps it in memory only.
+    path: Option<PathBuf>,
+
+    outcomes: HashMap<MutationKey, Outcome>,
+}
+
+impl State {
+    /// Load the state stored under `root`, or start an empty one if there is none yet.
+    pub fn load(root: &Path) -> Result<State> {
+        let path = root.join(STATE_PATH);
+        let outcomes = if path.is_file() {
+            let json = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read state from {:?}", path))?;
+            let entries: Vec<Entry> = serde_json::from_str(&json)
+                .with_context(|| format!("failed to parse state in {:?}", path))?;
+            entries.into_iter().map(|e| (e.key, e.outcome)).collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(State {
+            path: Some(path),
+            outcomes,
+        })
+    }
+
+    /// Start from an empty state that is saved under `root`, forgetting earlier runs.
+    pub fn empty(root: &Path) -> State {
+        State {
+            path: Some(root.join(STATE_PATH)),
+            outcomes: HashMap::new(),
+        }
+    }
+
+    /// Return the outcome of the mutation identified by `key`, if it was decided.
+    pub fn get(&self, key: &MutationKey) -> Option<Outcome> {
+        self.outcomes.get(key).copied()
+    }
+
+    /// True if the mutation identified by `key` is known to break the check.
+    pub fn is_rejected(&self, key: &MutationKey) -> bool {
+        self.get(key) == Some(Outcome::Rejected)
+    }
+
+    /// Number of remembered outcomes.
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    /// Remember the outcome of a mutation and save the state.
+    pub fn record(&mut self, key: MutationKey, outcome: Outcome) -> Result<()> {
+        self.outcomes.insert(key, outcome);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut entries: Vec<Entry> = self
+            .outcomes
+            .iter()
+            .map(|(key, outcome)| Entry {
+                key: key.clone(),
+                outcome: *outcome,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {:?}", parent))?;
+        }
+        // Write to a temporary file first so an interruption can't leave a truncated
+        // state behind.
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&entries)?)
+            .with_context(|| format!("failed to write state to {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to write state to {:?}", path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{mutation::MutationOp, source::SourceFile};
+
+    fn key(item: &str) -> MutationKey {
+        MutationKey {
+            path: PathBuf::from("src/lib.rs"),
+            item: item.to_owned(),
+            op: MutationOp::ToVisCrate,
+            context: 42,
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let root = tempfile::tempdir().unwrap();
+        let mut state = State::load(root.path()).unwrap();
+        assert!(state.is_empty());
+        state.record(key("Foo"), Outcome::Rejected).unwrap();
+        state.record(key("Bar"), Outcome::Accepted).unwrap();
+        assert!(root.path().join(STATE_PATH).is_file());
+
+        let loaded = State::load(root.path()).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.is_rejected(&key("Foo")));
+        assert_eq!(loaded.get(&key("Bar")), Some(Outcome::Accepted));
+        assert_eq!(loaded.get(&key("Baz")), None);
+
+        assert!(State::empty(root.path()).is_empty());
+    }
+
+    #[test]
+    fn entries_are_flat() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join(STATE_PATH);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            r#"[{"path": "src/lib.rs", "item": "Foo", "op": "ToVisCrate", "context": 42,
+                "outcome": "TimedOut"}]"#,
+        )
+        .unwrap();
+        let state = State::load(root.path()).unwrap();
+        assert_eq!(state.get(&key("Foo")), Some(Outcome::TimedOut));
+    }
+
+    #[test]
+    fn context_ignores_unrelated_code() {
+        let keys = |code: &str| -> Vec<MutationKey> {
+            SourceFile::from_code(Path::new("src/lib.rs"), code)
+                .mutations()
+                .unwrap()
+                .iter()
+                .filter(|m| m.item() == "Foo")
+                .map(|m| m.key())
+                .collect()
+        };
+        let before = keys("pub struct Foo {\n    a: u8,\n}\n");
+        let moved = keys("fn f() {}\n\n// Comment.\npub struct Foo {\n    a: u8,\n}\n");
+        let changed = keys("pub struct Foo {\n    a: u16,\n}\n");
+        assert!(!before.is_empty());
+        assert_eq!(before, moved);
+        assert_ne!(before, changed);
+    }
+}
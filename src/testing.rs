@@ -0,0 +1,48 @@
+//! Fixtures shared by the tests of several modules.
+
+use crate::git::git_output;
+use std::path::Path;
+
+/// Return the manifest of a package named `name`.
+pub fn manifest(name: &str) -> String {
+    format!(
+        "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        name
+    )
+}
+
+/// Write `files`, given as paths relative to `dir` and their contents, creating
+/// directories as needed.
+pub fn write_files(dir: &Path, files: &[(&str, &str)]) {
+    for (path, contents) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+}
+
+/// Create a git repo in `dir` with `files` committed.
+pub fn repo_in(dir: &Path, files: &[(&str, &str)]) {
+    write_files(dir, files);
+    for args in [
+        &["init", "--quiet"][..],
+        &["config", "user.name", "Test"],
+        &["config", "user.email", "test@example.com"],
+        &["add", "."],
+        &["commit", "--quiet", "--message", "Initial"],
+    ] {
+        git_output(args, dir, None).unwrap();
+    }
+}
+
+/// Create a package named `demo` in `dir` with `files`, which may replace its manifest,
+/// committed to a new git repo.
+pub fn crate_in(dir: &Path, files: &[(&str, &str)]) {
+    let manifest = manifest("demo");
+    let mut all = vec![
+        ("Cargo.toml", manifest.as_str()),
+        (".gitignore", "/target\nCargo.lock\n"),
+    ];
+    all.extend_from_slice(files);
+    repo_in(dir, &all);
+}
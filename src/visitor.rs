@@ -1,7 +1,11 @@
 use crate::{
+    editor::Span,
+    index::content_hash,
     mutation::{Mutation, MutationOp},
     source::SourceFile,
 };
+use anyhow::Result;
+use syn::spanned::Spanned;
 use syn::visit::Visit;
 
 /// `syn` visitor that recursively traverses the syntax tree, accumulating
@@ -10,8 +14,17 @@ pub struct Visitor<'sf> {
     /// All the mutations generated by visiting the file.
     pub mutations: Vec<Mutation>,
 
+    /// Errors from spans that `syn` reported outside the file, if any.
+    pub errors: Vec<anyhow::Error>,
+
     /// The file being visited.
     source_file: &'sf SourceFile,
+
+    /// Names of the items enclosing the node being visited, outermost first.
+    item_path: Vec<String>,
+
+    /// Hash of the code of the innermost `struct`, `enum` or `fn` being visited.
+    context: u64,
 }
 
 impl<'sf> Visitor<'sf> {
@@ -19,10 +32,55 @@ impl<'sf> Visitor<'sf> {
         Self {
             source_file,
             mutations: Vec::new(),
+            errors: Vec::new(),
+            item_path: Vec::new(),
+            context: 0,
+        }
+    }
+
+    /// Visit the insides of the item called `name` with that name on the item path.
+    fn in_item(&mut self, name: String, visit: impl FnOnce(&mut Self)) {
+        self.item_path.push(name);
+        visit(self);
+        self.item_path.pop();
+    }
+
+    /// Like [Visitor::in_item], for an item whose code is the context of mutations
+    /// within it.
+    fn in_context_item(
+        &mut self,
+        name: String,
+        node: &impl Spanned,
+        visit: impl FnOnce(&mut Self),
+    ) {
+        let code = &self.source_file.code;
+        let context = Span::from(node.span())
+            .byte_range(code)
+            .map_or(0, |range| content_hash(&code[range]));
+        let outer_context = std::mem::replace(&mut self.context, context);
+        self.in_item(name, visit);
+        self.context = outer_context;
+    }
+
+    fn mutation(&self, op: MutationOp, span: Span) -> Result<Mutation> {
+        Mutation::new(
+            self.source_file.clone(),
+            op,
+            span,
+            self.item_path.join("::"),
+            self.context,
+        )
+    }
+
+    /// Add the mutations of `vis`, or the error that prevented making them.
+    fn add_ops_for_visibility(&mut self, vis: &syn::Visibility) {
+        match self.ops_for_visibility(vis) {
+            Ok(mutations) => self.mutations.extend(mutations),
+            Err(err) => self.errors.push(err),
         }
     }
 
-    fn ops_for_visibility(&self, vis: &syn::Visibility) -> Vec<Mutation> {
+    fn ops_for_visibility(&self, vis: &syn::Visibility) -> Result<Vec<Mutation>> {
         match vis {
             syn::Visibility::Public(pv) => {
                 let ops = &[
@@ -32,9 +90,7 @@ impl<'sf> Visitor<'sf> {
                     MutationOp::ToVisSuper,
                 ];
                 let span = pv.pub_token.span.into();
-                ops.iter()
-                    .map(|op| Mutation::new(self.source_file.clone(), *op, span))
-                    .collect()
+                ops.iter().map(|op| self.mutation(*op, span)).collect()
             }
             syn::Visibility::Crate(cv) => {
                 let ops = &[
@@ -43,52 +99,201 @@ impl<'sf> Visitor<'sf> {
                     MutationOp::ToVisInherited,
                 ];
                 let span = cv.crate_token.span.into();
-                ops.iter()
-                    .map(|op| Mutation::new(self.source_file.clone(), *op, span))
-                    .collect()
+                ops.iter().map(|op| self.mutation(*op, span)).collect()
+            }
+            syn::Visibility::Restricted(rv) => {
+                let ops: &[MutationOp] = match restricted_scope(rv) {
+                    RestrictedScope::Crate => &[
+                        MutationOp::ToVisSelf,
+                        MutationOp::ToVisSuper,
+                        MutationOp::ToVisInherited,
+                    ],
+                    RestrictedScope::Super | RestrictedScope::Path => {
+                        &[MutationOp::ToVisSelf, MutationOp::ToVisInherited]
+                    }
+                    RestrictedScope::SelfMod => &[MutationOp::ToVisInherited],
+                };
+                // The span covers the whole `pub(...)` group, not only the
+                // `pub` token, so that the replacement drops the parens too.
+                let span = Span {
+                    start: rv.pub_token.span.start().into(),
+                    end: rv.paren_token.span.end().into(),
+                };
+                ops.iter().map(|op| self.mutation(*op, span)).collect()
             }
-            syn::Visibility::Restricted(..) => vec![], // TODO support
-            syn::Visibility::Inherited => vec![],
+            syn::Visibility::Inherited => Ok(vec![]),
         }
     }
 }
 
+/// The scope named by a restricted visibility, `pub(...)`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum RestrictedScope {
+    /// `pub(crate)` or `pub(in crate)`
+    Crate,
+    /// `pub(super)` or `pub(in super)`
+    Super,
+    /// `pub(self)` or `pub(in self)`
+    SelfMod,
+    /// `pub(in some::other::path)`
+    Path,
+}
+
+fn restricted_scope(rv: &syn::VisRestricted) -> RestrictedScope {
+    if rv.path.leading_colon.is_some() || rv.path.segments.len() != 1 {
+        return RestrictedScope::Path;
+    }
+    let ident = &rv.path.segments[0].ident;
+    if ident == "crate" {
+        RestrictedScope::Crate
+    } else if ident == "super" {
+        RestrictedScope::Super
+    } else if ident == "self" {
+        RestrictedScope::SelfMod
+    } else {
+        RestrictedScope::Path
+    }
+}
+
 impl<'ast, 'sf> Visit<'ast> for Visitor<'sf> {
     /// Visit `struct`
     fn visit_item_struct(&mut self, node: &'ast syn::ItemStruct) {
-        self.mutations.extend(self.ops_for_visibility(&node.vis));
-        syn::visit::visit_fields(self, &node.fields);
+        self.in_context_item(node.ident.to_string(), node, |v| {
+            v.add_ops_for_visibility(&node.vis);
+            v.visit_fields(&node.fields);
+        });
     }
 
     /// Visit `fn`
     fn visit_item_fn(&mut self, node: &'ast syn::ItemFn) {
-        self.mutations.extend(self.ops_for_visibility(&node.vis));
+        self.in_context_item(node.sig.ident.to_string(), node, |v| {
+            v.add_ops_for_visibility(&node.vis);
+        });
     }
 
     /// Visit `enum`
     fn visit_item_enum(&mut self, node: &'ast syn::ItemEnum) {
-        self.mutations.extend(self.ops_for_visibility(&node.vis));
-        for variant in node.variants.iter() {
-            syn::visit::visit_variant(self, &variant);
-        }
+        self.in_context_item(node.ident.to_string(), node, |v| {
+            v.add_ops_for_visibility(&node.vis);
+            for variant in node.variants.iter() {
+                v.visit_variant(variant);
+            }
+        });
+    }
+
+    /// Visit inline `mod`
+    fn visit_item_mod(&mut self, node: &'ast syn::ItemMod) {
+        self.in_item(node.ident.to_string(), |v| {
+            syn::visit::visit_item_mod(v, node);
+        });
     }
 
     /// Visit `enum` variants
     fn visit_variant(&mut self, node: &'ast syn::Variant) {
-        syn::visit::visit_fields(self, &node.fields);
+        self.in_item(node.ident.to_string(), |v| v.visit_fields(&node.fields));
     }
 
-    /// Visit fields of structs, enums etc
+    /// Visit named fields of structs, enums etc
     fn visit_fields(&mut self, node: &'ast syn::Fields) {
         if let syn::Fields::Named(named) = node {
             for field in named.named.iter() {
-                syn::visit::visit_field(self, field);
+                let name = field.ident.as_ref().map(ToString::to_string);
+                self.in_item(name.unwrap_or_default(), |v| v.visit_field(field));
             }
         }
     }
 
     /// Visit field, wherever it is
     fn visit_field(&mut self, node: &'ast syn::Field) {
-        self.mutations.extend(self.ops_for_visibility(&node.vis))
+        self.add_ops_for_visibility(&node.vis)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    use crate::{mutation::MutationOp, source::SourceFile};
+
+    fn mutated(code: &str) -> Vec<(MutationOp, String)> {
+        let sf = SourceFile::from_code(Path::new("src/lib.rs"), code);
+        sf.mutations()
+            .unwrap()
+            .iter()
+            .map(|m| (m.op, m.mutate()))
+            .collect()
+    }
+
+    #[test]
+    fn restricted_crate_narrows_whole_group() {
+        assert_eq!(
+            mutated("pub(crate) struct Foo;\n"),
+            vec![
+                (MutationOp::ToVisSelf, "pub(self) struct Foo;\n".to_string()),
+                (
+                    MutationOp::ToVisSuper,
+                    "pub(super) struct Foo;\n".to_string()
+                ),
+                (MutationOp::ToVisInherited, "struct Foo;\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn restricted_super_and_self() {
+        assert_eq!(
+            mutated("pub(super) fn foo() {}\n"),
+            vec![
+                (MutationOp::ToVisSelf, "pub(self) fn foo() {}\n".to_string()),
+                (MutationOp::ToVisInherited, "fn foo() {}\n".to_string()),
+            ]
+        );
+        assert_eq!(
+            mutated("pub(self) fn foo() {}\n"),
+            vec![(MutationOp::ToVisInherited, "fn foo() {}\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn restricted_in_path() {
+        assert_eq!(
+            mutated("struct Foo {\n    pub(in crate::a) bar: u8,\n}\n"),
+            vec![
+                (
+                    MutationOp::ToVisSelf,
+                    "struct Foo {\n    pub(self) bar: u8,\n}\n".to_string()
+                ),
+                (
+                    MutationOp::ToVisInherited,
+                    "struct Foo {\n    bar: u8,\n}\n".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            mutated("pub(in crate) struct Foo;\n").len(),
+            3,
+            "`pub(in crate)` is the same as `pub(crate)`"
+        );
+    }
+
+    #[test]
+    fn mutations_name_their_enclosing_item() {
+        let sf = SourceFile::from_code(
+            Path::new("src/lib.rs"),
+            "mod inner {\n    pub struct Foo(pub u8, pub(crate) u8);\n    pub struct Bar { pub a: u8 }\n}\npub(crate) fn f() {}\n",
+        );
+        let mut items: Vec<String> = sf
+            .mutations()
+            .unwrap()
+            .iter()
+            .map(|m| m.item().to_owned())
+            .collect();
+        items.dedup();
+        assert_eq!(
+            items,
+            vec!["inner::Foo", "inner::Bar", "inner::Bar::a", "f"],
+            "tuple fields are left alone"
+        );
     }
 }
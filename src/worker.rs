@@ -0,0 +1,183 @@
+//! Workers that check batches in parallel, each in a `git worktree` of its own so that
+//! they neither see each other's mutations nor share a target directory.
+
+use crate::{
+    batch::Batch,
+    config::{CheckConfig, Timeouts},
+    git::{git_output, run_git, GitResult},
+    pipeline::{verify, Scope, Verdict},
+};
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Where the workers' worktrees are created, relative to the tree root.
+pub const WORKTREES_DIR: &str = "target/retypist/worktrees";
+
+/// A batch for a worker to check.
+#[derive(Debug)]
+pub struct Job {
+    /// Number of the attempt, as counted by the session.
+    pub attempt: usize,
+    /// Commit the batch's mutations were generated from, and that the worker checks
+    /// them on top of.
+    pub base: String,
+    pub batch: Batch,
+    /// Packages to check the batch over.
+    pub scope: Scope,
+    /// File to log the check's output to.
+    pub log: PathBuf,
+}
+
+/// A job as checked by a worker.
+#[derive(Debug)]
+pub struct Done {
+    /// Index of the worker that checked the job, which is now free for another.
+    pub worker: usize,
+    pub job: Job,
+    pub verdict: Result<Verdict>,
+}
+
+/// A thread that checks jobs in its own worktree, one at a time.
+pub struct Worker {
+    /// Root of the worktree, as passed to `git worktree`.
+    worktree: PathBuf,
+    jobs: Sender<Job>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    /// Create worker `index` with a fresh worktree of the tree at `root`, reporting
+    /// every job it finishes to `done`.
+    ///
+    /// `prefix` is the tree root's path within its git repository, as printed by
+    /// `git rev-parse --show-prefix`.
+    pub fn spawn(
+        index: usize,
+        root: &Path,
+        prefix: &str,
+        check: CheckConfig,
+        timeouts: Timeouts,
+        done: Sender<Done>,
+    ) -> Result<Worker> {
+        let worktree = root
+            .canonicalize()?
+            .join(WORKTREES_DIR)
+            .join(index.to_string());
+        let worktree_arg = worktree.to_string_lossy().into_owned();
+        if worktree.exists() {
+            // Left behind by an earlier run that didn't finish cleanly.
+            run_git(
+                &["worktree", "remove", "--force", &worktree_arg],
+                root,
+                timeouts.git(),
+            )?;
+            if worktree.exists() {
+                std::fs::remove_dir_all(&worktree)
+                    .with_context(|| format!("failed to remove {:?}", worktree))?;
+            }
+            run_git(&["worktree", "prune"], root, timeouts.git())?;
+        }
+        let added = run_git(
+            &["worktree", "add", "--detach", &worktree_arg, "HEAD"],
+            root,
+            timeouts.git(),
+        )?;
+        if !added.success() {
+            return Err(anyhow!("failed to create worktree {:?}", worktree));
+        }
+        // The part of the worktree that corresponds to the session's tree root.
+        let thread_root = worktree.join(prefix);
+        let thread_worktree = worktree.clone();
+        Ok(Worker::start(index, worktree, done, move |job| {
+            check_job(&thread_worktree, &thread_root, &check, timeouts, job)
+        }))
+    }
+
+    /// Create worker `index` that gives every job the verdict of `check`, without a
+    /// worktree, so the session can be tested with verdicts made up on the spot.
+    #[cfg(test)]
+    pub(crate) fn fake(
+        index: usize,
+        done: Sender<Done>,
+        check: impl Fn(&Job) -> Result<Verdict> + Send + 'static,
+    ) -> Worker {
+        Worker::start(index, PathBuf::new(), done, check)
+    }
+
+    /// Start the thread of worker `index`, which reports the verdict of `check` on
+    /// every job to `done`.
+    fn start(
+        index: usize,
+        worktree: PathBuf,
+        done: Sender<Done>,
+        check: impl Fn(&Job) -> Result<Verdict> + Send + 'static,
+    ) -> Worker {
+        let (jobs, job_queue) = channel::<Job>();
+        let thread = thread::spawn(move || {
+            for job in job_queue {
+                let verdict = check(&job);
+                let finished = Done {
+                    worker: index,
+                    job,
+                    verdict,
+                };
+                if done.send(finished).is_err() {
+                    break;
+                }
+            }
+        });
+        Worker {
+            worktree,
+            jobs,
+            thread,
+        }
+    }
+
+    /// Hand `job` to the worker.
+    pub fn send(&self, job: Job) -> Result<()> {
+        self.jobs
+            .send(job)
+            .map_err(|_| anyhow!("worker for {:?} stopped", self.worktree))
+    }
+
+    /// Wait for the worker's current job, if any, then remove its worktree, killing git
+    /// if it runs for longer than `timeout`.
+    pub fn finish(self, root: &Path, timeout: Option<Duration>) -> Result<()> {
+        drop(self.jobs);
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("worker for {:?} panicked", self.worktree))?;
+        let worktree_arg = self.worktree.to_string_lossy();
+        // Unlike `run_git`, this still works after an interrupt.
+        git_output(
+            &["worktree", "remove", "--force", &worktree_arg],
+            root,
+            timeout,
+        )?;
+        Ok(())
+    }
+}
+
+/// Check out the job's base in the worktree, apply its batch and check it.
+fn check_job(
+    worktree: &Path,
+    root: &Path,
+    check: &CheckConfig,
+    timeouts: Timeouts,
+    job: &Job,
+) -> Result<Verdict> {
+    match run_git(
+        &["checkout", "--detach", "--force", &job.base],
+        worktree,
+        timeouts.git(),
+    )? {
+        GitResult::Success => {}
+        GitResult::Failure => return Err(anyhow!("failed to check out {}", job.base)),
+        GitResult::Timeout => return Err(anyhow!("checking out {} timed out", job.base)),
+    }
+    job.batch.apply_in(root)?;
+    verify(check, &job.scope, root, &job.log, timeouts.cargo())
+}